@@ -0,0 +1,48 @@
+//! A stand-in for the Hackatime spans endpoint, for running the heatmap without network access.
+//!
+//! Listens on `MOCK_ADDR` and serves `<id>.json` from `MOCK_FIXTURES_DIR`, see `mock.rs`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[path = "../ids.rs"]
+mod ids;
+#[path = "../mock.rs"]
+mod mock;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:9999";
+const DEFAULT_SLOW_MS: u64 = 3000;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let addr = std::env::var("MOCK_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+    let slow_ms = match std::env::var("MOCK_SLOW_MS").map(|v| v.parse::<u64>()) {
+        Ok(Ok(slow_ms)) => slow_ms,
+        Ok(Err(_)) => {
+            tracing::error!("Invalid MOCK_SLOW_MS: must be a non-negative number");
+            return;
+        }
+        Err(_) => DEFAULT_SLOW_MS,
+    };
+    let app = mock::router(
+        std::env::var("MOCK_FIXTURES_DIR")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(PathBuf::from),
+        Duration::from_millis(slow_ms),
+    );
+
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Failed to bind to {}: {}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("Mock Hackatime listening on http://{}", addr);
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("Server error: {}", e);
+    }
+}
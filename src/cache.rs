@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use metrics::counter;
+use moka::sync::Cache;
+
+pub enum Lookup<V> {
+    Fresh(V),
+    Stale(V),
+    Miss,
+}
+
+/// A moka cache that keeps entries past their time to live for a grace window,
+/// so they can still be served while a fresh value is fetched in the background.
+#[derive(Clone)]
+pub struct SwrCache<K, V> {
+    name: &'static str,
+    entries: Cache<K, (Instant, V)>,
+    time_to_live: Duration,
+    refreshing: Arc<Mutex<HashSet<K>>>,
+}
+
+/// Marks a key as being refreshed until dropped.
+pub struct RefreshGuard<K: Hash + Eq> {
+    refreshing: Arc<Mutex<HashSet<K>>>,
+    key: K,
+}
+
+impl<K: Hash + Eq> Drop for RefreshGuard<K> {
+    fn drop(&mut self) {
+        self.refreshing.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl<K, V> SwrCache<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        name: &'static str,
+        max_capacity: u64,
+        time_to_live: Duration,
+        stale_grace: Duration,
+    ) -> Self {
+        Self {
+            name,
+            entries: Cache::builder()
+                .max_capacity(max_capacity)
+                .time_to_live(time_to_live + stale_grace)
+                .build(),
+            time_to_live,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Lookup<V> {
+        let lookup = match self.entries.get(key) {
+            Some((inserted_at, value)) if inserted_at.elapsed() < self.time_to_live => {
+                Lookup::Fresh(value)
+            }
+            Some((_, value)) => Lookup::Stale(value),
+            None => Lookup::Miss,
+        };
+
+        let outcome = match lookup {
+            Lookup::Fresh(_) => "fresh",
+            Lookup::Stale(_) => "stale",
+            Lookup::Miss => "miss",
+        };
+        counter!("heatmap_cache_lookups_total", "cache" => self.name, "outcome" => outcome)
+            .increment(1);
+
+        lookup
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Inserts a value that was produced `age` ago, e.g. one loaded from disk,
+    /// and returns it classified as fresh or stale.
+    pub fn restore(&self, key: K, value: V, age: Duration) -> Lookup<V> {
+        let inserted_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        self.entries.insert(key, (inserted_at, value.clone()));
+        if age < self.time_to_live {
+            Lookup::Fresh(value)
+        } else {
+            Lookup::Stale(value)
+        }
+    }
+
+    /// Claims the background refresh of `key`, returns `None` if one is already running.
+    pub fn begin_refresh(&self, key: &K) -> Option<RefreshGuard<K>> {
+        if !self.refreshing.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        counter!("heatmap_cache_background_refreshes_total", "cache" => self.name).increment(1);
+        Some(RefreshGuard {
+            refreshing: self.refreshing.clone(),
+            key: key.clone(),
+        })
+    }
+}
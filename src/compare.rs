@@ -0,0 +1,55 @@
+use chrono::{Datelike, NaiveDate};
+
+use crate::DayBuckets;
+
+const LAST_YEAR: &str = "last_year";
+
+/// What a comparison heatmap is measured against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Baseline {
+    /// The same users one year earlier.
+    LastYear,
+    /// Other users (or a team) over the same days.
+    Members(String),
+}
+
+impl Baseline {
+    pub fn parse(value: &str) -> Self {
+        if value.eq_ignore_ascii_case(LAST_YEAR) {
+            Self::LastYear
+        } else {
+            Self::Members(value.to_string())
+        }
+    }
+
+    /// The days that have to be loaded to compare against `start..=end`.
+    pub fn period(&self, start: NaiveDate, end: NaiveDate) -> (NaiveDate, NaiveDate) {
+        match self {
+            Self::LastYear => (one_year_earlier(start), one_year_earlier(end)),
+            Self::Members(_) => (start, end),
+        }
+    }
+
+    /// Keys the baseline by the displayed dates it is compared with.
+    pub fn align(&self, day_buckets: &DayBuckets, dates: &[NaiveDate]) -> DayBuckets {
+        dates
+            .iter()
+            .filter_map(|date| {
+                let baseline_date = match self {
+                    Self::LastYear => one_year_earlier(*date),
+                    Self::Members(_) => *date,
+                };
+                day_buckets
+                    .get(&baseline_date)
+                    .map(|seconds| (*date, *seconds))
+            })
+            .collect()
+    }
+}
+
+/// February 29th maps to February 28th.
+fn one_year_earlier(date: NaiveDate) -> NaiveDate {
+    date.with_year(date.year() - 1)
+        .or_else(|| NaiveDate::from_ymd_opt(date.year() - 1, date.month(), date.day() - 1))
+        .unwrap_or(date)
+}
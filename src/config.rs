@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use reqwest::Url;
+
+use crate::ids::is_safe_file_id;
+
+const DEFAULT_HACKATIME_BASE_URL: &str = "https://hackatime.hackclub.com";
+const DEFAULT_HACKATIME_SPANS_PATH: &str = "/api/v1/users/{id}/heartbeats/spans";
+const DEFAULT_WAKAPI_BASE_URL: &str = "https://wakapi.dev";
+const DEFAULT_WAKAPI_SUMMARIES_PATH: &str = "/api/compat/wakatime/v1/users/{id}/summaries";
+const DEFAULT_CACHE_STALE_GRACE_SECONDS: u64 = 60 * 60; // (1 hour)
+const DEFAULT_UPSTREAM_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_UPSTREAM_RETRY_BASE_DELAY_MS: u64 = 200;
+const DEFAULT_UPSTREAM_RETRY_MAX_DELAY_MS: u64 = 2000;
+const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_BREAKER_OPEN_SECONDS: u64 = 30;
+const DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_UPSTREAM_READ_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_UPSTREAM_DEADLINE_MS: u64 = 8000;
+const DEFAULT_UPSTREAM_POOL_MAX_IDLE_PER_HOST: usize = 16;
+const DEFAULT_UPSTREAM_POOL_IDLE_TIMEOUT_SECONDS: u64 = 90;
+const DEFAULT_UPSTREAM_MAX_CONCURRENCY: usize = 16;
+const DEFAULT_UPSTREAM_MAX_CONCURRENCY_PER_USER: usize = 2;
+const DEFAULT_UPSTREAM_RATE_LIMIT_BACKOFF_SECONDS: u64 = 60;
+pub const MAX_BACKOFF_SECONDS: u64 = 60 * 60 * 24; // (1 day)
+const DEFAULT_UPSTREAM_USER_AGENT: &str = concat!(
+    "hackatime-heatmap/",
+    env!("CARGO_PKG_VERSION"),
+    " (+",
+    env!("CARGO_PKG_REPOSITORY"),
+    ")"
+);
+const DEFAULT_DISK_CACHE_TTL_SECONDS: u64 = 60 * 60 * 24; // (1 day)
+const DEFAULT_INCREMENTAL_OPEN_DAYS: u32 = 2;
+const DEFAULT_HISTORY_MAX_USERS: u64 = 1000;
+const DEFAULT_TEAM_MAX_MEMBERS: usize = 25;
+const DEFAULT_INGEST_SPAN_TIMEOUT_SECONDS: u64 = 120;
+const DEFAULT_MIN_SPAN_SECONDS: u64 = 0;
+const DEFAULT_MAX_RANGE_DAYS: u32 = 366;
+const DEFAULT_GIT_SESSION_GAP_MINUTES: u64 = 120;
+const DEFAULT_GIT_SESSION_FIRST_COMMIT_MINUTES: u64 = 30;
+const USER_ID_PLACEHOLDER: &str = "{id}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    Hackatime,
+    Wakapi,
+    File,
+    Fixture,
+    Local,
+    Git,
+}
+
+impl SourceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Hackatime => "hackatime",
+            Self::Wakapi => "wakapi",
+            Self::File => "file",
+            Self::Fixture => "fixture",
+            Self::Local => "local",
+            Self::Git => "git",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "hackatime" => Ok(Self::Hackatime),
+            "wakapi" | "wakatime" => Ok(Self::Wakapi),
+            "file" => Ok(Self::File),
+            "fixture" => Ok(Self::Fixture),
+            "local" => Ok(Self::Local),
+            "git" => Ok(Self::Git),
+            _ => Err(format!(
+                "Invalid ACTIVITY_SOURCE '{}': must be hackatime, wakapi, file, fixture, local or git",
+                value
+            )),
+        }
+    }
+}
+
+/// What the git source shows for a day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitMode {
+    /// Estimated coding time, from sessions of commits close to each other.
+    Sessions,
+    /// Number of commits.
+    Commits,
+}
+
+impl GitMode {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "sessions" => Ok(Self::Sessions),
+            "commits" => Ok(Self::Commits),
+            _ => Err(format!(
+                "Invalid GIT_ACTIVITY '{}': must be sessions or commits",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub hackatime_base_url: String,
+    pub hackatime_spans_path: String,
+    pub wakapi_base_url: String,
+    pub wakapi_summaries_path: String,
+    pub wakapi_api_key: Option<String>,
+    pub activity_source: SourceKind,
+    pub activity_source_path: Option<PathBuf>,
+    pub cache_stale_grace: Duration,
+    pub upstream_max_attempts: u32,
+    pub upstream_retry_base_delay: Duration,
+    pub upstream_retry_max_delay: Duration,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_open_duration: Duration,
+    pub upstream_connect_timeout: Duration,
+    pub upstream_read_timeout: Duration,
+    /// Total time an upstream fetch may take, retries included.
+    pub upstream_deadline: Duration,
+    pub upstream_pool_max_idle_per_host: usize,
+    pub upstream_pool_idle_timeout: Duration,
+    pub upstream_user_agent: String,
+    pub upstream_max_concurrency: usize,
+    pub upstream_max_concurrency_per_user: usize,
+    pub upstream_rate_limit_backoff: Duration,
+    pub disk_cache_dir: Option<PathBuf>,
+    pub disk_cache_ttl: Duration,
+    pub incremental_open_days: u32,
+    pub history_max_users: u64,
+    pub team_groups: HashMap<String, Vec<String>>,
+    pub team_max_members: usize,
+    /// Maps each ingestion API key to the user it belongs to.
+    pub ingest_api_keys: HashMap<String, String>,
+    pub ingest_span_timeout: Duration,
+    pub import_dir: Option<PathBuf>,
+    pub min_span_duration: Duration,
+    pub max_range_days: u32,
+    pub git_mode: GitMode,
+    pub git_session_gap: Duration,
+    pub git_session_first_commit: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let base_url = env_or("HACKATIME_BASE_URL", DEFAULT_HACKATIME_BASE_URL);
+        let spans_path = env_or("HACKATIME_SPANS_PATH", DEFAULT_HACKATIME_SPANS_PATH);
+        let wakapi_base_url = env_or("WAKAPI_BASE_URL", DEFAULT_WAKAPI_BASE_URL);
+        let wakapi_summaries_path = env_or("WAKAPI_SUMMARIES_PATH", DEFAULT_WAKAPI_SUMMARIES_PATH);
+        let wakapi_api_key = env::var("WAKAPI_API_KEY")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+
+        let activity_source = SourceKind::parse(&env_or("ACTIVITY_SOURCE", "hackatime"))?;
+        let activity_source_path = env::var("ACTIVITY_SOURCE_PATH")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(PathBuf::from);
+        let cache_stale_grace = env_seconds(
+            "CACHE_STALE_GRACE_SECONDS",
+            DEFAULT_CACHE_STALE_GRACE_SECONDS,
+        )?;
+        let upstream_max_attempts =
+            env_parse("UPSTREAM_MAX_ATTEMPTS", DEFAULT_UPSTREAM_MAX_ATTEMPTS)?;
+        if upstream_max_attempts == 0 {
+            return Err("Invalid UPSTREAM_MAX_ATTEMPTS '0': must be at least 1".to_string());
+        }
+        let upstream_retry_base_delay = Duration::from_millis(env_parse(
+            "UPSTREAM_RETRY_BASE_DELAY_MS",
+            DEFAULT_UPSTREAM_RETRY_BASE_DELAY_MS,
+        )?);
+        let upstream_retry_max_delay = Duration::from_millis(env_parse(
+            "UPSTREAM_RETRY_MAX_DELAY_MS",
+            DEFAULT_UPSTREAM_RETRY_MAX_DELAY_MS,
+        )?);
+        let circuit_breaker_threshold = env_parse(
+            "CIRCUIT_BREAKER_THRESHOLD",
+            DEFAULT_CIRCUIT_BREAKER_THRESHOLD,
+        )?;
+        let circuit_breaker_open_duration = env_seconds(
+            "CIRCUIT_BREAKER_OPEN_SECONDS",
+            DEFAULT_CIRCUIT_BREAKER_OPEN_SECONDS,
+        )?;
+        let upstream_connect_timeout = Duration::from_millis(env_parse(
+            "UPSTREAM_CONNECT_TIMEOUT_MS",
+            DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS,
+        )?);
+        let upstream_read_timeout = Duration::from_millis(env_parse(
+            "UPSTREAM_READ_TIMEOUT_MS",
+            DEFAULT_UPSTREAM_READ_TIMEOUT_MS,
+        )?);
+        if upstream_connect_timeout.is_zero() || upstream_read_timeout.is_zero() {
+            return Err("Upstream connect and read timeouts must be greater than 0".to_string());
+        }
+        let upstream_deadline = Duration::from_millis(env_parse(
+            "UPSTREAM_DEADLINE_MS",
+            DEFAULT_UPSTREAM_DEADLINE_MS,
+        )?);
+        if upstream_deadline.is_zero() {
+            return Err("Invalid UPSTREAM_DEADLINE_MS '0': must be greater than 0".to_string());
+        }
+        let upstream_pool_max_idle_per_host = env_parse(
+            "UPSTREAM_POOL_MAX_IDLE_PER_HOST",
+            DEFAULT_UPSTREAM_POOL_MAX_IDLE_PER_HOST,
+        )?;
+        let upstream_pool_idle_timeout = env_seconds(
+            "UPSTREAM_POOL_IDLE_TIMEOUT_SECONDS",
+            DEFAULT_UPSTREAM_POOL_IDLE_TIMEOUT_SECONDS,
+        )?;
+        let upstream_user_agent = env_or("UPSTREAM_USER_AGENT", DEFAULT_UPSTREAM_USER_AGENT);
+        if reqwest::header::HeaderValue::from_str(&upstream_user_agent).is_err() {
+            return Err(format!(
+                "Invalid UPSTREAM_USER_AGENT '{}': must be a valid header value",
+                upstream_user_agent
+            ));
+        }
+        let upstream_max_concurrency =
+            env_parse("UPSTREAM_MAX_CONCURRENCY", DEFAULT_UPSTREAM_MAX_CONCURRENCY)?;
+        let upstream_max_concurrency_per_user = env_parse(
+            "UPSTREAM_MAX_CONCURRENCY_PER_USER",
+            DEFAULT_UPSTREAM_MAX_CONCURRENCY_PER_USER,
+        )?;
+        if upstream_max_concurrency == 0 || upstream_max_concurrency_per_user == 0 {
+            return Err("Upstream concurrency limits must be at least 1".to_string());
+        }
+        let upstream_rate_limit_backoff = env_seconds(
+            "UPSTREAM_RATE_LIMIT_BACKOFF_SECONDS",
+            DEFAULT_UPSTREAM_RATE_LIMIT_BACKOFF_SECONDS,
+        )?;
+        if circuit_breaker_open_duration.as_secs() > MAX_BACKOFF_SECONDS
+            || upstream_rate_limit_backoff.as_secs() > MAX_BACKOFF_SECONDS
+        {
+            return Err(format!(
+                "CIRCUIT_BREAKER_OPEN_SECONDS and UPSTREAM_RATE_LIMIT_BACKOFF_SECONDS must be at most {}",
+                MAX_BACKOFF_SECONDS
+            ));
+        }
+        let disk_cache_dir = env::var("DISK_CACHE_DIR")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(PathBuf::from);
+        let disk_cache_ttl = env_seconds("DISK_CACHE_TTL_SECONDS", DEFAULT_DISK_CACHE_TTL_SECONDS)?;
+        let history_max_users = env_parse("HISTORY_MAX_USERS", DEFAULT_HISTORY_MAX_USERS)?;
+        let team_groups = parse_team_groups(&env_or("TEAM_GROUPS", ""))?;
+        let team_max_members = env_parse("TEAM_MAX_MEMBERS", DEFAULT_TEAM_MAX_MEMBERS)?;
+        if team_max_members == 0 {
+            return Err("Invalid TEAM_MAX_MEMBERS '0': must be at least 1".to_string());
+        }
+        let ingest_api_keys = parse_ingest_api_keys(&env_or("INGEST_API_KEYS", ""))?;
+        if activity_source == SourceKind::Local && ingest_api_keys.is_empty() {
+            return Err("INGEST_API_KEYS is required for the local source".to_string());
+        }
+        let ingest_span_timeout = env_seconds(
+            "INGEST_SPAN_TIMEOUT_SECONDS",
+            DEFAULT_INGEST_SPAN_TIMEOUT_SECONDS,
+        )?;
+        let import_dir = import_dir();
+        let min_span_duration = env_seconds("MIN_SPAN_SECONDS", DEFAULT_MIN_SPAN_SECONDS)?;
+        let max_range_days = env_parse("MAX_RANGE_DAYS", DEFAULT_MAX_RANGE_DAYS)?;
+        if max_range_days == 0 {
+            return Err("Invalid MAX_RANGE_DAYS '0': must be at least 1".to_string());
+        }
+        let incremental_open_days =
+            env_parse("INCREMENTAL_OPEN_DAYS", DEFAULT_INCREMENTAL_OPEN_DAYS)?;
+        if incremental_open_days > max_range_days {
+            return Err(format!(
+                "Invalid INCREMENTAL_OPEN_DAYS '{}': must be at most MAX_RANGE_DAYS ({})",
+                incremental_open_days, max_range_days
+            ));
+        }
+        let git_mode = GitMode::parse(&env_or("GIT_ACTIVITY", "sessions"))?;
+        let git_session_gap =
+            env_minutes("GIT_SESSION_GAP_MINUTES", DEFAULT_GIT_SESSION_GAP_MINUTES)?;
+        let git_session_first_commit = env_minutes(
+            "GIT_SESSION_FIRST_COMMIT_MINUTES",
+            DEFAULT_GIT_SESSION_FIRST_COMMIT_MINUTES,
+        )?;
+
+        Ok(Self {
+            hackatime_base_url: validate_base_url("HACKATIME_BASE_URL", &base_url)?,
+            hackatime_spans_path: validate_path("HACKATIME_SPANS_PATH", &spans_path)?,
+            wakapi_base_url: validate_base_url("WAKAPI_BASE_URL", &wakapi_base_url)?,
+            wakapi_summaries_path: validate_path("WAKAPI_SUMMARIES_PATH", &wakapi_summaries_path)?,
+            wakapi_api_key,
+            activity_source,
+            activity_source_path,
+            cache_stale_grace,
+            upstream_max_attempts,
+            upstream_retry_base_delay,
+            upstream_retry_max_delay,
+            circuit_breaker_threshold,
+            circuit_breaker_open_duration,
+            upstream_connect_timeout,
+            upstream_read_timeout,
+            upstream_deadline,
+            upstream_pool_max_idle_per_host,
+            upstream_pool_idle_timeout,
+            upstream_user_agent,
+            upstream_max_concurrency,
+            upstream_max_concurrency_per_user,
+            upstream_rate_limit_backoff,
+            disk_cache_dir,
+            disk_cache_ttl,
+            incremental_open_days,
+            history_max_users,
+            team_groups,
+            team_max_members,
+            ingest_api_keys,
+            ingest_span_timeout,
+            import_dir,
+            min_span_duration,
+            max_range_days,
+            git_mode,
+            git_session_gap,
+            git_session_first_commit,
+        })
+    }
+
+    pub fn spans_url(&self, id: &str) -> String {
+        format!(
+            "{}{}",
+            self.hackatime_base_url,
+            self.hackatime_spans_path.replace(USER_ID_PLACEHOLDER, id)
+        )
+    }
+
+    pub fn summaries_url(&self, id: &str) -> String {
+        format!(
+            "{}{}",
+            self.wakapi_base_url,
+            self.wakapi_summaries_path.replace(USER_ID_PLACEHOLDER, id)
+        )
+    }
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn env_parse<T: FromStr + ToString>(name: &str, default: T) -> Result<T, String> {
+    let value = env_or(name, &default.to_string());
+    value.parse::<T>().map_err(|_| {
+        format!(
+            "Invalid {} '{}': must be a non-negative number",
+            name, value
+        )
+    })
+}
+
+fn env_seconds(name: &str, default: u64) -> Result<Duration, String> {
+    env_parse(name, default).map(Duration::from_secs)
+}
+
+fn env_minutes(name: &str, default: u64) -> Result<Duration, String> {
+    env_parse(name, default).map(|minutes: u64| Duration::from_secs(minutes.saturating_mul(60)))
+}
+
+fn validate_base_url(name: &str, value: &str) -> Result<String, String> {
+    let url = Url::parse(value).map_err(|e| format!("Invalid {} '{}': {}", name, value, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "Invalid {} '{}': scheme must be http or https",
+            name, value
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Invalid {} '{}': missing host", name, value));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!(
+            "Invalid {} '{}': must not contain a query or fragment",
+            name, value
+        ));
+    }
+
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+/// Parses `name=id,id;name=id` into the members of each named team.
+fn parse_team_groups(value: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut groups = HashMap::new();
+    for group in value.split(';').map(str::trim).filter(|g| !g.is_empty()) {
+        let (name, members) = group
+            .split_once('=')
+            .map(|(name, members)| (name.trim(), crate::team::parse_member_list(members)))
+            .filter(|(name, members)| !name.is_empty() && !members.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "Invalid TEAM_GROUPS entry '{}': must be name=id,id,...",
+                    group
+                )
+            })?;
+        groups.insert(name.to_string(), members);
+    }
+    Ok(groups)
+}
+
+/// `IMPORT_DIR`, also read on its own by the `import` command, which needs nothing else.
+pub fn import_dir() -> Option<PathBuf> {
+    env::var("IMPORT_DIR")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// Parses `user=key;user=key` into a lookup from API key to user.
+fn parse_ingest_api_keys(value: &str) -> Result<HashMap<String, String>, String> {
+    let mut keys = HashMap::new();
+    for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (user, key) = entry
+            .split_once('=')
+            .map(|(user, key)| (user.trim(), key.trim()))
+            .filter(|(user, key)| !user.is_empty() && !key.is_empty())
+            .ok_or_else(|| "Invalid INGEST_API_KEYS entry: must be user=key".to_string())?;
+        if !is_safe_file_id(user) {
+            return Err(format!("Invalid INGEST_API_KEYS user '{}'", user));
+        }
+        if keys.insert(key.to_string(), user.to_string()).is_some() {
+            return Err(format!(
+                "Invalid INGEST_API_KEYS: the key of '{}' is used more than once",
+                user
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+fn validate_path(name: &str, value: &str) -> Result<String, String> {
+    if !value.starts_with('/') {
+        return Err(format!("Invalid {} '{}': must start with '/'", name, value));
+    }
+    if !value.contains(USER_ID_PLACEHOLDER) {
+        return Err(format!(
+            "Invalid {} '{}': must contain the {} placeholder",
+            name, value, USER_ID_PLACEHOLDER
+        ));
+    }
+    if value.contains('?') || value.contains('#') {
+        return Err(format!(
+            "Invalid {} '{}': must not contain a query or fragment",
+            name, value
+        ));
+    }
+
+    Ok(value.to_string())
+}
@@ -0,0 +1,285 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+
+use crate::UserDateRange;
+use crate::source::Activity;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskEntry {
+    key: String,
+    written_at: u64,
+    activity: Activity,
+}
+
+/// How often expired entries are removed from disk.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Persists fetched activity as one JSON file per user window so it survives restarts.
+/// Expired files are ignored when looked up and removed by a periodic sweep, since most
+/// windows move with the date and are never looked up again.
+pub struct DiskCache {
+    dir: PathBuf,
+    time_to_live: Duration,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, time_to_live: Duration) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Failed to create disk cache directory {}: {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(Self { dir, time_to_live })
+    }
+
+    fn entry_key(user_range: &UserDateRange) -> String {
+        format!(
+            "{}|{}|{}|{}|{}",
+            user_range.id,
+            user_range
+                .filter
+                .pairs()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&"),
+            user_range.timezone.map_or("UTC", |tz| tz.name()),
+            user_range.start.naive_utc(),
+            user_range.end.naive_utc()
+        )
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        // FNV-1a, stable across builds unlike the std hasher
+        let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.dir.join(format!("{:016x}.json", hash))
+    }
+
+    /// Returns the cached activity together with its age.
+    pub async fn load(&self, user_range: &UserDateRange) -> Option<(Activity, Duration)> {
+        let key = Self::entry_key(user_range);
+        let path = self.entry_path(&key);
+
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Error reading {}: {}", path.display(), err);
+                }
+                counter!("heatmap_cache_lookups_total", "cache" => "disk", "outcome" => "miss")
+                    .increment(1);
+                return None;
+            }
+        };
+
+        let entry = match serde_json::from_slice::<DiskEntry>(&bytes) {
+            Ok(entry) if entry.key == key => entry,
+            Ok(_) => {
+                counter!("heatmap_cache_lookups_total", "cache" => "disk", "outcome" => "miss")
+                    .increment(1);
+                return None;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Discarding corrupt disk cache entry {}: {}",
+                    path.display(),
+                    err
+                );
+                let _ = tokio::fs::remove_file(&path).await;
+                counter!("heatmap_cache_lookups_total", "cache" => "disk", "outcome" => "miss")
+                    .increment(1);
+                return None;
+            }
+        };
+
+        let age = Duration::from_secs(unix_now().saturating_sub(entry.written_at));
+        if age >= self.time_to_live {
+            let _ = tokio::fs::remove_file(&path).await;
+            counter!("heatmap_cache_lookups_total", "cache" => "disk", "outcome" => "expired")
+                .increment(1);
+            return None;
+        }
+
+        counter!("heatmap_cache_lookups_total", "cache" => "disk", "outcome" => "hit").increment(1);
+        Some((entry.activity, age))
+    }
+
+    pub async fn store(&self, user_range: &UserDateRange, activity: &Activity) {
+        let key = Self::entry_key(user_range);
+        let path = self.entry_path(&key);
+        let entry = DiskEntry {
+            key,
+            written_at: unix_now(),
+            activity: activity.clone(),
+        };
+
+        let bytes = match serde_json::to_vec(&entry) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                tracing::error!("Error serializing disk cache entry: {}", err);
+                return;
+            }
+        };
+
+        // write to a temporary file first so readers never see a partial entry
+        let tmp_path = path.with_extension("json.tmp");
+        let result = match tokio::fs::write(&tmp_path, &bytes).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::warn!("Error writing {}: {}", path.display(), err);
+        }
+    }
+
+    /// Sweeps expired entries now and then every hour.
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let disk_cache = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                disk_cache.sweep().await;
+            }
+        });
+    }
+
+    /// Removes entries written longer than the time to live ago, judged by the modification
+    /// time of their file, and temporary files left behind by interrupted writes.
+    pub async fn sweep(&self) -> usize {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!("Error reading {}: {}", self.dir.display(), err);
+                return 0;
+            }
+        };
+
+        let mut removed = 0;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "json" && extension != "tmp")
+            {
+                continue;
+            }
+            let age = match entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+            {
+                Ok(modified) => modified.elapsed().unwrap_or_default(),
+                Err(_) => continue,
+            };
+            if age >= self.time_to_live && tokio::fs::remove_file(&path).await.is_ok() {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            tracing::info!("Removed {} expired disk cache entries", removed);
+            counter!("heatmap_disk_cache_swept_total").increment(removed as u64);
+        }
+        removed
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "heatmap-disk-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn activity() -> Activity {
+        Activity::Days(HashMap::from([(
+            NaiveDate::from_ymd_opt(2025, 6, 2).unwrap(),
+            3600,
+        )]))
+    }
+
+    #[tokio::test]
+    async fn entries_survive_a_restart() {
+        let dir = temp_dir("restart");
+        let window = UserDateRange::test_window("alice");
+        DiskCache::new(dir.clone(), Duration::from_secs(60))
+            .unwrap()
+            .store(&window, &activity())
+            .await;
+
+        let reopened = DiskCache::new(dir.clone(), Duration::from_secs(60)).unwrap();
+        let Some((Activity::Days(days), age)) = reopened.load(&window).await else {
+            panic!("expected the stored entry");
+        };
+        assert_eq!(
+            days,
+            HashMap::from([(NaiveDate::from_ymd_opt(2025, 6, 2).unwrap(), 3600)])
+        );
+        assert!(age < Duration::from_secs(60));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_dropped() {
+        let dir = temp_dir("expired");
+        let window = UserDateRange::test_window("alice");
+        let disk_cache = DiskCache::new(dir.clone(), Duration::ZERO).unwrap();
+        disk_cache.store(&window, &activity()).await;
+
+        assert!(disk_cache.load(&window).await.is_none());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn corrupt_entries_are_discarded() {
+        let dir = temp_dir("corrupt");
+        let window = UserDateRange::test_window("alice");
+        let disk_cache = DiskCache::new(dir.clone(), Duration::from_secs(60)).unwrap();
+        let path = disk_cache.entry_path(&DiskCache::entry_key(&window));
+        std::fs::write(&path, b"{\"key\":").unwrap();
+
+        assert!(disk_cache.load(&window).await.is_none());
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_expired_files() {
+        let dir = temp_dir("sweep");
+        let window = UserDateRange::test_window("alice");
+        let disk_cache = DiskCache::new(dir.clone(), Duration::ZERO).unwrap();
+        disk_cache.store(&window, &activity()).await;
+        std::fs::write(dir.join("notes.txt"), b"not an entry").unwrap();
+
+        assert_eq!(disk_cache.sweep().await, 1);
+        assert!(dir.join("notes.txt").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
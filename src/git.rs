@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::DateTime;
+use metrics::counter;
+use tokio::process::Command;
+
+use crate::config::{Config, GitMode};
+use crate::source::{Activity, ActivitySource, SourceFuture, Unit, span_in_range};
+use crate::{DayBuckets, FetchUserSpansError, Span, UserDateRange};
+
+const GIT_LOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Activity of a commit author (`id` is the author email) in a local git repository.
+pub struct GitSource {
+    repo: PathBuf,
+    project: Option<String>,
+    mode: GitMode,
+    session_gap: f64,
+    first_commit: f64,
+}
+
+impl GitSource {
+    pub fn new(repo: PathBuf, config: &Config) -> Result<Self, String> {
+        let repo = repo
+            .canonicalize()
+            .map_err(|e| format!("Invalid git repository {}: {}", repo.display(), e))?;
+        Ok(Self {
+            project: repo
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            repo,
+            mode: config.git_mode,
+            session_gap: config.git_session_gap.as_secs_f64(),
+            first_commit: config.git_session_first_commit.as_secs_f64(),
+        })
+    }
+
+    /// Author timestamps of every non-merge commit by `email`, oldest first.
+    async fn commit_times(&self, email: &str) -> Result<Vec<i64>, FetchUserSpansError> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .args(["log", "--all", "--no-merges", "--format=%ae %at"])
+            .kill_on_drop(true)
+            .output();
+        let output = match tokio::time::timeout(GIT_LOG_TIMEOUT, output).await {
+            Ok(Ok(output)) if output.status.success() => output,
+            Ok(Ok(output)) => {
+                tracing::error!(
+                    "git log failed in {}: {}",
+                    self.repo.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+                return Err(FetchUserSpansError::Fetch);
+            }
+            Ok(Err(err)) => {
+                tracing::error!("Error running git: {}", err);
+                counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+                return Err(FetchUserSpansError::Fetch);
+            }
+            Err(_) => {
+                tracing::error!("git log timed out in {}", self.repo.display());
+                counter!("heatmap_upstream_errors_total", "type" => "timeout").increment(1);
+                return Err(FetchUserSpansError::Timeout);
+            }
+        };
+
+        let mut times: Vec<i64> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter(|(author, _)| author.eq_ignore_ascii_case(email))
+            .filter_map(|(_, time)| time.parse().ok())
+            .collect();
+        if times.is_empty() {
+            counter!("heatmap_upstream_errors_total", "type" => "not_found").increment(1);
+            return Err(FetchUserSpansError::NotFound);
+        }
+        times.sort_unstable();
+        Ok(times)
+    }
+
+    /// Estimates sessions the way git-hours does: commits less than `session_gap` apart belong
+    /// to the same session, and each session starts `first_commit` before its first commit.
+    fn sessions(&self, times: &[i64]) -> Vec<Span> {
+        let mut spans: Vec<Span> = Vec::new();
+        for &time in times {
+            let time = time as f64;
+            match spans.last_mut() {
+                Some(span) if time - span.end_time <= self.session_gap => span.end_time = time,
+                _ => spans.push(Span {
+                    start_time: time - self.first_commit,
+                    end_time: time,
+                    duration: 0.0,
+                    project: self.project.clone(),
+                    language: None,
+                    editor: None,
+                }),
+            }
+        }
+        spans
+            .into_iter()
+            .map(|span| Span {
+                duration: span.end_time - span.start_time,
+                ..span
+            })
+            .filter(|span| span.duration > 0.0)
+            .collect()
+    }
+
+    fn commits_per_day(&self, times: &[i64], user_range: &UserDateRange) -> DayBuckets {
+        let tz = user_range.timezone.unwrap_or(chrono_tz::UTC);
+        let (start, end) = (user_range.start.timestamp(), user_range.end.timestamp());
+        let mut day_buckets = DayBuckets::new();
+        for time in times.iter().filter(|time| (start..=end).contains(*time)) {
+            if let Some(date) = DateTime::from_timestamp(*time, 0) {
+                *day_buckets
+                    .entry(date.with_timezone(&tz).date_naive())
+                    .or_insert(0) += 1;
+            }
+        }
+        day_buckets
+    }
+}
+
+impl ActivitySource for GitSource {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn daily_totals(&self) -> bool {
+        self.mode == GitMode::Commits
+    }
+
+    fn unit(&self) -> Unit {
+        match self.mode {
+            GitMode::Sessions => Unit::Seconds,
+            GitMode::Commits => Unit::Commits,
+        }
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        _http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let times = self.commit_times(&user_range.id).await?;
+            match self.mode {
+                GitMode::Sessions => Ok(Activity::Spans(
+                    self.sessions(&times)
+                        .into_iter()
+                        .filter(|span| span_in_range(span, user_range))
+                        .collect(),
+                )),
+                GitMode::Commits => {
+                    // commits only know their project, so check the filter against that
+                    let repo_span = Span {
+                        start_time: 0.0,
+                        end_time: 0.0,
+                        duration: 0.0,
+                        project: self.project.clone(),
+                        language: None,
+                        editor: None,
+                    };
+                    if !user_range.filter.matches(&repo_span) {
+                        return Ok(Activity::Days(DayBuckets::new()));
+                    }
+                    Ok(Activity::Days(self.commits_per_day(&times, user_range)))
+                }
+            }
+        })
+    }
+}
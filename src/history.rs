@@ -0,0 +1,273 @@
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDate};
+use moka::sync::Cache;
+
+use crate::DayBuckets;
+use crate::source::SpanFilter;
+use crate::time::Calendar;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HistoryKey {
+    pub id: String,
+    pub calendar: Calendar,
+    pub filter: SpanFilter,
+}
+
+/// Per-day totals for days that are old enough to never change again.
+#[derive(Debug)]
+pub struct History {
+    days: DayBuckets,
+    covered_from: NaiveDate,
+    finalized_through: NaiveDate,
+}
+
+impl History {
+    pub fn finalized_through(&self) -> NaiveDate {
+        self.finalized_through
+    }
+
+    fn days_between(&self, start: NaiveDate, end: NaiveDate) -> DayBuckets {
+        self.days
+            .iter()
+            .filter(|(date, _)| **date >= start && **date <= end)
+            .map(|(date, seconds)| (*date, *seconds))
+            .collect()
+    }
+}
+
+pub enum Plan {
+    /// Nothing usable is stored, fetch the whole range.
+    Full,
+    /// Fetch only the days after `History::finalized_through`.
+    Partial(Arc<History>),
+    /// Every requested day is finalized.
+    Complete(DayBuckets),
+}
+
+/// Keeps finalized days per user so that only the still open window needs to be fetched again.
+pub struct HistoryStore {
+    entries: Cache<HistoryKey, Arc<History>>,
+    open_days: u32,
+}
+
+impl HistoryStore {
+    pub fn new(max_capacity: u64, open_days: u32) -> Self {
+        Self {
+            entries: Cache::builder().max_capacity(max_capacity).build(),
+            open_days,
+        }
+    }
+
+    pub fn plan(&self, key: &HistoryKey, start: NaiveDate, end: NaiveDate) -> Plan {
+        if self.open_days == 0 {
+            return Plan::Full;
+        }
+        match self.entries.get(key) {
+            Some(history) if history.covered_from <= start && history.finalized_through >= end => {
+                Plan::Complete(history.days_between(start, end))
+            }
+            Some(history)
+                if history.covered_from <= start && history.finalized_through >= start =>
+            {
+                Plan::Partial(history)
+            }
+            _ => Plan::Full,
+        }
+    }
+
+    /// Combines the stored history with freshly fetched days and finalizes every fetched day
+    /// that is now outside the open window. `fetched` must cover the days after
+    /// `base.finalized_through` (or the whole range when there is no base).
+    pub fn merge(
+        &self,
+        key: &HistoryKey,
+        start: NaiveDate,
+        end: NaiveDate,
+        today: NaiveDate,
+        base: Option<&History>,
+        fetched: DayBuckets,
+    ) -> DayBuckets {
+        let fetched_after = base.map_or(start - Duration::days(1), |h| h.finalized_through);
+        let mut merged =
+            base.map_or_else(DayBuckets::new, |h| h.days_between(start, fetched_after));
+        merged.extend(
+            fetched
+                .into_iter()
+                .filter(|(date, _)| *date > fetched_after && *date <= end),
+        );
+
+        if self.open_days == 0 {
+            return merged;
+        }
+
+        let Some(cutoff) = today.checked_sub_signed(Duration::days(self.open_days as i64)) else {
+            return merged;
+        };
+        let finalized_through = cutoff.min(end);
+        if finalized_through < start {
+            return merged;
+        }
+
+        let finalized = merged
+            .iter()
+            .filter(|(date, _)| **date <= finalized_through)
+            .map(|(date, seconds)| (*date, *seconds));
+        let history = match self.entries.get(key) {
+            // extend the stored history when both ranges touch, otherwise start over
+            Some(history)
+                if history.covered_from <= finalized_through + Duration::days(1)
+                    && history.finalized_through + Duration::days(1) >= start =>
+            {
+                let mut days = history.days.clone();
+                days.retain(|date, _| *date < start || *date > finalized_through);
+                days.extend(finalized);
+                History {
+                    days,
+                    covered_from: history.covered_from.min(start),
+                    finalized_through: history.finalized_through.max(finalized_through),
+                }
+            }
+            _ => History {
+                days: finalized.collect(),
+                covered_from: start,
+                finalized_through,
+            },
+        };
+        self.entries.insert(key.clone(), Arc::new(history));
+
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone};
+
+    use super::*;
+    use crate::source::{Activity, span_in_range};
+    use crate::{Span, UserDateRange};
+
+    fn calendar() -> Calendar {
+        Calendar {
+            tz: chrono_tz::Europe::Berlin,
+            day_start: NaiveTime::MIN,
+        }
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 3, day).unwrap()
+    }
+
+    /// A span starting at `hour:minute` local time on March `day` that lasts `minutes`.
+    fn span(day: u32, hour: u32, minute: u32, minutes: i64) -> Span {
+        let start = calendar()
+            .tz
+            .from_local_datetime(&date(day).and_hms_opt(hour, minute, 0).unwrap())
+            .unwrap()
+            .timestamp() as f64;
+        Span {
+            start_time: start,
+            end_time: start + minutes as f64 * 60.0,
+            duration: minutes as f64 * 60.0,
+            project: None,
+            language: None,
+            editor: None,
+        }
+    }
+
+    /// Buckets the spans a source would return for the local dates `from..=end`.
+    fn fetch(spans: &[Span], from: NaiveDate, end: NaiveDate) -> DayBuckets {
+        let window = UserDateRange::covering("alice", &SpanFilter::default(), from, end, None);
+        Activity::Spans(
+            spans
+                .iter()
+                .filter(|span| span_in_range(span, &window))
+                .cloned()
+                .collect(),
+        )
+        .into_day_buckets(&calendar())
+    }
+
+    fn load(store: &HistoryStore, spans: &[Span], start: NaiveDate, end: NaiveDate) -> DayBuckets {
+        let key = HistoryKey {
+            id: "alice".to_string(),
+            calendar: calendar(),
+            filter: SpanFilter::default(),
+        };
+        match store.plan(&key, start, end) {
+            Plan::Complete(days) => days,
+            Plan::Partial(history) => {
+                let fetched = fetch(spans, history.finalized_through(), end);
+                store.merge(&key, start, end, end, Some(&history), fetched)
+            }
+            Plan::Full => {
+                let fetched = fetch(spans, start, end);
+                store.merge(&key, start, end, end, None, fetched)
+            }
+        }
+    }
+
+    #[test]
+    fn incremental_merges_match_full_fetches() {
+        let spans = [
+            span(1, 10, 0, 90),
+            // crosses midnight into the first open day of the first render
+            span(8, 23, 30, 60),
+            // crosses midnight into the open window of the second render
+            span(9, 23, 0, 120),
+            span(10, 14, 0, 45),
+            span(11, 0, 15, 30),
+        ];
+        let store = HistoryStore::new(100, 2);
+
+        // the first render finalizes everything up to March 8th
+        let first = load(&store, &spans, date(1), date(10));
+        assert_eq!(
+            first,
+            load(&HistoryStore::new(100, 2), &spans, date(1), date(10))
+        );
+
+        // a day later the range moves, and only the days after March 8th are fetched again
+        let store_second = load(&store, &spans, date(2), date(11));
+        let full_second = load(&HistoryStore::new(100, 2), &spans, date(2), date(11));
+        assert_eq!(store_second, full_second);
+        assert_eq!(full_second.get(&date(9)), Some(&(30 * 60 + 60 * 60)));
+        assert_eq!(full_second.get(&date(10)), Some(&(60 * 60 + 45 * 60)));
+
+        // once finalized, the days come from the history alone
+        let key = HistoryKey {
+            id: "alice".to_string(),
+            calendar: calendar(),
+            filter: SpanFilter::default(),
+        };
+        let Plan::Complete(days) = store.plan(&key, date(2), date(8)) else {
+            panic!("expected finalized days");
+        };
+        assert_eq!(
+            days,
+            load(&HistoryStore::new(100, 2), &spans, date(2), date(8))
+        );
+    }
+
+    #[test]
+    fn huge_open_windows_finalize_nothing() {
+        let store = HistoryStore::new(100, u32::MAX);
+        let spans = [span(1, 10, 0, 90)];
+
+        let days = load(&store, &spans, date(1), date(10));
+        assert_eq!(days, fetch(&spans, date(1), date(10)));
+        assert!(matches!(
+            store.plan(
+                &HistoryKey {
+                    id: "alice".to_string(),
+                    calendar: calendar(),
+                    filter: SpanFilter::default(),
+                },
+                date(1),
+                date(10)
+            ),
+            Plan::Full
+        ));
+    }
+}
@@ -0,0 +1,5 @@
+/// Whether a user id can name a file inside a data directory without escaping it.
+/// Also used by the mock server, so this module must not depend on the rest of the crate.
+pub fn is_safe_file_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && !id.contains(['/', '\\'])
+}
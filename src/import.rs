@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use metrics::counter;
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+
+use crate::DayBuckets;
+use crate::ids::is_safe_file_id;
+
+/// How long a loaded import is kept before the file is read again, so new imports show up
+/// without a restart.
+const IMPORT_CACHE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Deserialize)]
+struct ExportDay {
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    range: Option<ExportRange>,
+    grand_total: ExportTotal,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportRange {
+    #[serde(default)]
+    date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportTotal {
+    #[serde(default)]
+    total_seconds: f64,
+}
+
+/// The WakaTime "export my code stats" file lists its days under `days`, a saved
+/// `/summaries` response (Hackatime, Wakapi) under `data`.
+#[derive(Debug, Deserialize)]
+struct Export {
+    #[serde(default, alias = "data")]
+    days: Vec<ExportDay>,
+}
+
+impl ExportDay {
+    fn date(&self) -> Option<NaiveDate> {
+        self.date
+            .as_deref()
+            .or(self.range.as_ref().and_then(|range| range.date.as_deref()))
+            .and_then(|value| value.get(..10))
+            .and_then(|value| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok())
+    }
+}
+
+/// Parses an export into seconds per day.
+pub fn parse_export(bytes: &[u8]) -> Result<BTreeMap<NaiveDate, u32>, String> {
+    let export: Export =
+        serde_json::from_slice(bytes).map_err(|e| format!("Invalid export file: {}", e))?;
+
+    let mut days = BTreeMap::new();
+    for day in export.days {
+        let date = day
+            .date()
+            .ok_or_else(|| "Invalid export file: a day has no valid date".to_string())?;
+        let seconds = day.grand_total.total_seconds.max(0.0).round() as u32;
+        *days.entry(date).or_insert(0) += seconds;
+    }
+    if days.is_empty() {
+        return Err("Invalid export file: no days found".to_string());
+    }
+    Ok(days)
+}
+
+/// Imported day totals of one user, used instead of live data for every day before `cutoff`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportedDays {
+    pub cutoff: NaiveDate,
+    pub days: BTreeMap<NaiveDate, u32>,
+}
+
+impl ImportedDays {
+    /// Days between `start` and `end` that are before the cutoff.
+    pub fn days_between(&self, start: NaiveDate, end: NaiveDate) -> DayBuckets {
+        let end = end.min(self.cutoff.pred_opt().unwrap_or(self.cutoff));
+        if start > end {
+            return DayBuckets::new();
+        }
+        self.days
+            .range(start..=end)
+            .map(|(date, seconds)| (*date, *seconds))
+            .collect()
+    }
+}
+
+/// Stores imported day totals as `<dir>/<id>.json`.
+pub struct ImportStore {
+    dir: PathBuf,
+    entries: Cache<String, Option<Arc<ImportedDays>>>,
+}
+
+impl ImportStore {
+    pub fn new(dir: PathBuf) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create import directory {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir,
+            entries: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(IMPORT_CACHE_DURATION)
+                .build(),
+        })
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        if !is_safe_file_id(id) {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+
+    pub async fn load(&self, id: &str) -> Option<Arc<ImportedDays>> {
+        if let Some(entry) = self.entries.get(id) {
+            return entry;
+        }
+
+        let path = self.path(id)?;
+        let entry = match tokio::fs::read(&path).await {
+            Ok(bytes) => match serde_json::from_slice::<ImportedDays>(&bytes) {
+                Ok(imported) => Some(Arc::new(imported)),
+                Err(err) => {
+                    tracing::warn!("Ignoring invalid import {}: {}", path.display(), err);
+                    None
+                }
+            },
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Error reading {}: {}", path.display(), err);
+                }
+                None
+            }
+        };
+        let outcome = if entry.is_some() { "hit" } else { "miss" };
+        counter!("heatmap_import_lookups_total", "outcome" => outcome).increment(1);
+        self.entries.insert(id.to_string(), entry.clone());
+        entry
+    }
+
+    /// Adds `days` to the stored import of `id`, replacing days that were imported before.
+    /// The cutoff defaults to the day after the last imported day.
+    pub fn import(
+        &self,
+        id: &str,
+        days: BTreeMap<NaiveDate, u32>,
+        cutoff: Option<NaiveDate>,
+    ) -> Result<ImportedDays, String> {
+        let path = self
+            .path(id)
+            .ok_or_else(|| format!("Invalid user id '{}'", id))?;
+        let last_day = days.keys().next_back().copied();
+
+        let mut imported = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<ImportedDays>(&bytes)
+                .map_err(|e| format!("Invalid import {}: {}", path.display(), e))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => ImportedDays {
+                cutoff: NaiveDate::MIN,
+                days: BTreeMap::new(),
+            },
+            Err(err) => return Err(format!("Error reading {}: {}", path.display(), err)),
+        };
+        imported.days.extend(days);
+        imported.cutoff = match cutoff {
+            Some(cutoff) => cutoff,
+            None => imported.cutoff.max(
+                last_day
+                    .and_then(|day| day.succ_opt())
+                    .unwrap_or(NaiveDate::MIN),
+            ),
+        };
+
+        let bytes = serde_json::to_vec(&imported).map_err(|e| e.to_string())?;
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, &bytes)
+            .and_then(|()| std::fs::rename(&tmp_path, &path))
+            .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+        self.entries.invalidate(id);
+        Ok(imported)
+    }
+}
+
+/// `import <id> <export.json> [--cutoff YYYY-MM-DD]`
+pub fn run_command(dir: Option<&Path>, args: &[String]) -> Result<String, String> {
+    const USAGE: &str = "Usage: hackatime-heatmap import <id> <export.json> [--cutoff YYYY-MM-DD]";
+
+    let dir = dir.ok_or("IMPORT_DIR must be set to import data")?;
+    let (id, file, cutoff) = match args {
+        [id, file] => (id, file, None),
+        [id, file, flag, cutoff] if flag == "--cutoff" => {
+            let cutoff = NaiveDate::parse_from_str(cutoff, "%Y-%m-%d")
+                .map_err(|_| format!("Invalid cutoff '{}': must be YYYY-MM-DD", cutoff))?;
+            (id, file, Some(cutoff))
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let bytes = std::fs::read(file).map_err(|e| format!("Error reading {}: {}", file, e))?;
+    let days = parse_export(&bytes)?;
+    let day_count = days.len();
+    let imported = ImportStore::new(dir.to_path_buf())?.import(id, days, cutoff)?;
+
+    Ok(format!(
+        "Imported {} days for {}, imported data is used for days before {}",
+        day_count, id, imported.cutoff
+    ))
+}
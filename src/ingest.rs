@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::source::{Activity, ActivitySource, SourceFuture, span_in_range};
+use crate::{FetchUserSpansError, Span, UserDateRange};
+
+/// A heartbeat sent by a WakaTime plugin, keeping only the fields spans are built from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub time: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+    #[serde(default, skip_serializing)]
+    user_agent: Option<String>,
+}
+
+impl Heartbeat {
+    fn same_context(&self, other: &Heartbeat) -> bool {
+        self.project == other.project
+            && self.language == other.language
+            && self.editor == other.editor
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Bulk(Vec<Heartbeat>),
+    Single(Heartbeat),
+}
+
+/// `wakatime/v1.90.0 (linux-6.1) go1.22 vscode/1.89.0 vscode-wakatime/24.5.0` is `vscode`.
+fn editor_from_user_agent(user_agent: &str) -> Option<String> {
+    user_agent
+        .split_whitespace()
+        .filter(|token| !token.starts_with('('))
+        .filter_map(|token| token.split_once('/'))
+        .map(|(name, _)| name)
+        .find(|name| *name != "wakatime")
+        .map(str::to_string)
+}
+
+/// Joins heartbeats into spans: the time until the next heartbeat counts as long as it is
+/// within `timeout`, and a new span starts whenever the project, language or editor changes.
+pub fn heartbeats_to_spans(mut heartbeats: Vec<Heartbeat>, timeout: Duration) -> Vec<Span> {
+    let timeout = timeout.as_secs_f64();
+    heartbeats.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    let mut spans = Vec::new();
+    let mut current: Option<Span> = None;
+    for (i, heartbeat) in heartbeats.iter().enumerate() {
+        let span = current.get_or_insert_with(|| Span {
+            start_time: heartbeat.time,
+            end_time: heartbeat.time,
+            duration: 0.0,
+            project: heartbeat.project.clone(),
+            language: heartbeat.language.clone(),
+            editor: heartbeat.editor.clone(),
+        });
+
+        match heartbeats.get(i + 1) {
+            Some(next) if next.time - heartbeat.time <= timeout => {
+                span.end_time = next.time;
+                if !heartbeat.same_context(next) {
+                    spans.extend(current.take());
+                }
+            }
+            _ => spans.extend(current.take()),
+        }
+    }
+
+    spans
+        .into_iter()
+        .map(|span| Span {
+            duration: span.end_time - span.start_time,
+            ..span
+        })
+        .filter(|span| span.duration > 0.0)
+        .collect()
+}
+
+/// Appends heartbeats to `<dir>/<user>.ndjson`, one heartbeat per line.
+pub struct HeartbeatStore {
+    dir: PathBuf,
+    write_lock: Mutex<()>,
+}
+
+impl HeartbeatStore {
+    pub fn new(dir: PathBuf) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Failed to create heartbeat directory {}: {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(Self {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, user: &str) -> PathBuf {
+        self.dir.join(format!("{}.ndjson", user))
+    }
+
+    pub async fn append(&self, user: &str, heartbeats: &[Heartbeat]) -> Result<(), String> {
+        let mut lines = Vec::new();
+        for heartbeat in heartbeats {
+            serde_json::to_writer(&mut lines, heartbeat).map_err(|e| e.to_string())?;
+            lines.push(b'\n');
+        }
+
+        // one write per request under the lock keeps lines from interleaving
+        let _guard = self.write_lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(user))
+            .await
+            .map_err(|e| e.to_string())?;
+        file.write_all(&lines).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn load(&self, user: &str) -> Result<Vec<Heartbeat>, FetchUserSpansError> {
+        let path = self.path(user);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                tracing::error!("Error reading {}: {}", path.display(), err);
+                counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+                return Err(FetchUserSpansError::Fetch);
+            }
+        };
+
+        Ok(bytes
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(heartbeat) => Some(heartbeat),
+                Err(err) => {
+                    tracing::warn!("Skipping invalid heartbeat in {}: {}", path.display(), err);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Spans built from the heartbeats received by the ingestion endpoint.
+pub struct LocalSource {
+    store: Arc<HeartbeatStore>,
+    users: HashSet<String>,
+    span_timeout: Duration,
+}
+
+impl LocalSource {
+    pub fn new(store: Arc<HeartbeatStore>, users: HashSet<String>, span_timeout: Duration) -> Self {
+        Self {
+            store,
+            users,
+            span_timeout,
+        }
+    }
+}
+
+impl ActivitySource for LocalSource {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        _http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move {
+            if !self.users.contains(&user_range.id) {
+                counter!("heatmap_upstream_errors_total", "type" => "not_found").increment(1);
+                return Err(FetchUserSpansError::NotFound);
+            }
+            let heartbeats = self.store.load(&user_range.id).await?;
+            Ok(Activity::Spans(
+                heartbeats_to_spans(heartbeats, self.span_timeout)
+                    .into_iter()
+                    .filter(|span| span_in_range(span, user_range))
+                    .collect(),
+            ))
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Ingest {
+    store: Arc<HeartbeatStore>,
+    users_by_key: Arc<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IngestQuery {
+    api_key: Option<String>,
+}
+
+impl Ingest {
+    pub fn new(store: Arc<HeartbeatStore>, users_by_key: HashMap<String, String>) -> Self {
+        Self {
+            store,
+            users_by_key: Arc::new(users_by_key),
+        }
+    }
+
+    /// Accepts the key the way WakaTime clients send it: `Basic base64(key)`, `Bearer key`
+    /// or an `api_key` query parameter.
+    fn authenticate(&self, headers: &HeaderMap, api_key: Option<&str>) -> Option<&str> {
+        let key = match headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(value) => match value.split_once(' ') {
+                Some(("Basic", encoded)) => {
+                    let decoded = BASE64.decode(encoded.trim()).ok()?;
+                    let decoded = String::from_utf8(decoded).ok()?;
+                    decoded.split(':').next().unwrap_or_default().to_string()
+                }
+                Some(("Bearer", key)) => key.trim().to_string(),
+                _ => return None,
+            },
+            None => api_key?.to_string(),
+        };
+        self.users_by_key.get(&key).map(String::as_str)
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/api/v1/users/current/heartbeats", post(ingest_heartbeats))
+            .route(
+                "/api/v1/users/current/heartbeats.bulk",
+                post(ingest_heartbeats),
+            )
+            .with_state(self)
+    }
+}
+
+async fn ingest_heartbeats(
+    State(ingest): State<Ingest>,
+    headers: HeaderMap,
+    Query(query): Query<IngestQuery>,
+    body: Bytes,
+) -> Response {
+    let Some(user) = ingest.authenticate(&headers, query.api_key.as_deref()) else {
+        counter!("heatmap_ingest_requests_total", "outcome" => "unauthorized").increment(1);
+        return (StatusCode::UNAUTHORIZED, "Invalid API key").into_response();
+    };
+
+    let (mut heartbeats, bulk) = match serde_json::from_slice::<Payload>(&body) {
+        Ok(Payload::Bulk(heartbeats)) => (heartbeats, true),
+        Ok(Payload::Single(heartbeat)) => (vec![heartbeat], false),
+        Err(err) => {
+            counter!("heatmap_ingest_requests_total", "outcome" => "invalid").increment(1);
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid heartbeat payload: {}", err),
+            )
+                .into_response();
+        }
+    };
+
+    let request_user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    let statuses: Vec<u16> = heartbeats
+        .iter()
+        .map(|heartbeat| {
+            if heartbeat.time.is_finite() && heartbeat.time > 0.0 {
+                201
+            } else {
+                400
+            }
+        })
+        .collect();
+    heartbeats.retain(|heartbeat| heartbeat.time.is_finite() && heartbeat.time > 0.0);
+    for heartbeat in &mut heartbeats {
+        if heartbeat.editor.is_none() {
+            heartbeat.editor = heartbeat
+                .user_agent
+                .as_deref()
+                .or(request_user_agent)
+                .and_then(editor_from_user_agent);
+        }
+    }
+
+    if let Err(err) = ingest.store.append(user, &heartbeats).await {
+        tracing::error!("Error storing heartbeats for {}: {}", user, err);
+        counter!("heatmap_ingest_requests_total", "outcome" => "error").increment(1);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to store heartbeats",
+        )
+            .into_response();
+    }
+    counter!("heatmap_ingest_requests_total", "outcome" => "ok").increment(1);
+    counter!("heatmap_ingest_heartbeats_total").increment(heartbeats.len() as u64);
+
+    // plugins match the statuses of a bulk request to the heartbeats they sent
+    let body = if bulk {
+        json!({
+            "responses": statuses
+                .iter()
+                .map(|status| json!([{ "data": null }, status]))
+                .collect::<Vec<_>>()
+        })
+    } else {
+        json!({ "data": null })
+    };
+    let status = if statuses.contains(&201) || bulk {
+        StatusCode::CREATED
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
@@ -0,0 +1,98 @@
+use chrono::Weekday;
+use svg::node::element::{Group, Rectangle, Text};
+
+use crate::palette::Color;
+use crate::time::{DAYS_PER_WEEK, weekday_row};
+use crate::utils::format_color;
+
+/// Room above the grid for month or hour labels.
+pub const MONTH_LABEL_HEIGHT: usize = 15;
+pub const WEEKDAY_LABEL_WIDTH: usize = 28;
+pub const LEGEND_HEIGHT: usize = 20;
+
+const WEEKDAY_LABELS: [(Weekday, &str); 3] = [
+    (Weekday::Mon, "Mon"),
+    (Weekday::Wed, "Wed"),
+    (Weekday::Fri, "Fri"),
+];
+
+/// Labels every other weekday row, for grids with a row per weekday starting on `week_start`.
+pub fn create_weekday_labels(
+    week_start: Weekday,
+    text_color: &str,
+    cell_size: usize,
+    padding: usize,
+    top: usize,
+) -> Group {
+    let mut group = Group::new();
+
+    for (weekday, label) in WEEKDAY_LABELS {
+        let y = top + weekday_row(weekday, week_start) * (cell_size + padding) + cell_size;
+        let text = Text::new(label)
+            .set("x", 0)
+            .set("y", y)
+            .set("fill", text_color)
+            .set("font-size", "10px")
+            .set("font-family", "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif");
+        group = group.add(text);
+    }
+    group
+}
+
+/// Width of the "Less ... More" legend, which has room for five colors by default.
+pub fn legend_width(color_count: usize, cell_size: usize) -> usize {
+    120 + color_count.saturating_sub(5) * (cell_size + 2)
+}
+
+/// Draws the legend below a grid of weekday rows that starts `top` pixels down, so that it
+/// ends at `right_edge`.
+pub fn create_legend(
+    colors: &[Color],
+    text_color: &str,
+    right_edge: usize,
+    cell_size: usize,
+    padding: usize,
+    top: usize,
+) -> Group {
+    let mut group = Group::new();
+    let legend_y = top + DAYS_PER_WEEK * (cell_size + padding) + 8;
+    let legend_start_x = right_edge.saturating_sub(legend_width(colors.len(), cell_size));
+
+    let less_text = Text::new("Less")
+        .set("x", legend_start_x)
+        .set("y", legend_y + 9)
+        .set("fill", text_color)
+        .set("font-size", "10px")
+        .set(
+            "font-family",
+            "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif",
+        );
+    group = group.add(less_text);
+
+    let box_start_x = legend_start_x + 28;
+    for (i, color) in colors.iter().enumerate() {
+        let color_str = format_color(color.0, color.1, color.2);
+        let rect = Rectangle::new()
+            .set("x", box_start_x + i * (cell_size + 2))
+            .set("y", legend_y)
+            .set("width", cell_size)
+            .set("height", cell_size)
+            .set("fill", color_str)
+            .set("rx", 2)
+            .set("ry", 2);
+        group = group.add(rect);
+    }
+
+    let more_text = Text::new("More")
+        .set("x", box_start_x + colors.len() * (cell_size + 2) + 2)
+        .set("y", legend_y + 9)
+        .set("fill", text_color)
+        .set("font-size", "10px")
+        .set(
+            "font-family",
+            "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif",
+        );
+    group = group.add(more_text);
+
+    group
+}
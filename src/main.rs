@@ -1,23 +1,45 @@
+mod cache;
+mod compare;
+mod config;
+mod disk_cache;
+mod git;
+mod history;
+mod ids;
+mod import;
+mod ingest;
+mod labels;
+#[cfg(test)]
+mod mock;
 mod palette;
+mod period;
+mod punchcard;
+mod sanitize;
+mod singleflight;
+mod source;
+mod team;
 mod time;
+mod upstream;
 mod utils;
+mod wakapi;
 
 use axum::Router;
 use axum::extract::{OriginalUri, Query, State};
-use axum::http::{HeaderMap as AxumHeaderMap, HeaderValue, StatusCode};
+use axum::http::{HeaderMap as AxumHeaderMap, HeaderValue, StatusCode, header};
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 
-use chrono::{DateTime, Datelike, NaiveDate};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
 use chrono_tz::Tz;
 
+use futures_util::future::{join_all, try_join};
+
 use tower_http::compression::CompressionLayer;
 use tower_http::decompression::DecompressionLayer;
 use tower_http::normalize_path::NormalizePathLayer;
 use tower_http::timeout::TimeoutLayer;
 
 use std::collections::HashMap;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 use std::time::{Duration, Instant};
 
 use tower_http::catch_panic::CatchPanicLayer;
@@ -25,27 +47,37 @@ use tower_http::cors::CorsLayer;
 
 use metrics::{counter, histogram};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use svg::Document;
 use svg::node::element::{Group, Rectangle, Text, Title};
 
-use moka::sync::Cache;
-
-use crate::palette::{PALETTES, get_palette};
-use crate::time::{create_timezone_date, generate_date_range, process_span_into_buckets};
-use crate::utils::{build_headers, format_cell_label, format_color, validate_ranges};
-
-const DEFAULT_ROWS: usize = 7;
-const DEFAULT_COLS: usize = 53;
-const MONTH_LABEL_HEIGHT: usize = 15;
-const WEEKDAY_LABEL_WIDTH: usize = 28;
-const LEGEND_HEIGHT: usize = 20;
+use crate::cache::{Lookup, SwrCache};
+use crate::compare::Baseline;
+use crate::config::{Config, SourceKind};
+use crate::disk_cache::DiskCache;
+use crate::history::{HistoryKey, HistoryStore, Plan};
+use crate::import::ImportStore;
+use crate::ingest::Ingest;
+use crate::labels::{
+    LEGEND_HEIGHT, MONTH_LABEL_HEIGHT, WEEKDAY_LABEL_WIDTH, create_legend, create_weekday_labels,
+    legend_width,
+};
+use crate::palette::{DIVERGING_PALETTES, PALETTES, get_diverging_palette, get_palette};
+use crate::punchcard::{HourBuckets, View, bucket_by_hour, create_punchcard_document};
+use crate::singleflight::SingleFlight;
+use crate::source::{Activity, ActivitySource, SpanFilter, Unit};
+use crate::team::{Aggregation, Teams};
+use crate::time::{
+    Calendar, DAYS_PER_WEEK, fetch_window, generate_date_range, parse_day_start, parse_week_start,
+};
+use crate::utils::{
+    build_headers, format_cell_label, format_color, format_comparison_label, validate_ranges,
+};
 
 const MONTH_LABELS: [&str; 12] = [
     "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
 ];
-const WEEKDAY_LABELS: [(usize, &str); 3] = [(1, "Mon"), (3, "Wed"), (5, "Fri")];
 const RESPONSE_CACHE_DURATION_SECONDS: u64 = 60 * 15; // (15 minutes)
 const MAX_RESPONSE_CACHE_ENTRIES: u64 = 1000;
 const REQUEST_CACHE_DURATION_SECONDS: u64 = 60 * 15; // (15 minutes)
@@ -81,15 +113,57 @@ fn svg_to_png(svg_str: &str) -> Result<Vec<u8>, String> {
 
 #[derive(Clone)]
 struct AppState {
-    response_cache: Cache<SvgParams, String>,
-    request_cache: Cache<UserDateRange, DayBuckets>,
+    http: reqwest::Client,
+    source: Arc<dyn ActivitySource>,
+    response_cache: SwrCache<SvgParams, String>,
+    request_cache: SwrCache<UserDateRange, Activity>,
+    disk_cache: Option<Arc<DiskCache>>,
+    history: Arc<HistoryStore>,
+    imports: Option<Arc<ImportStore>>,
+    teams: Arc<Teams>,
+    min_span_duration: Duration,
+    max_range_days: u32,
+    in_flight: Arc<SingleFlight<UserDateRange, Result<Activity, FetchUserSpansError>>>,
 }
 
+/// The window of activity fetched from the source for a user.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct UserDateRange {
     id: String,
-    start: DateTime<Tz>,
-    end: DateTime<Tz>,
+    filter: SpanFilter,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    /// Only set for sources reporting daily totals, as those depend on the timezone.
+    timezone: Option<Tz>,
+}
+
+impl UserDateRange {
+    /// Covers the local dates `start..=end` in every timezone and for every `day_start`, so
+    /// the spans fetched for one calendar can be bucketed again for any other without
+    /// another request. See `fetch_window` for ranges reaching today.
+    fn covering(
+        id: &str,
+        filter: &SpanFilter,
+        start: NaiveDate,
+        end: NaiveDate,
+        timezone: Option<Tz>,
+    ) -> Self {
+        let (start, end) = fetch_window(start, end, Utc::now().date_naive());
+        Self {
+            id: id.to_string(),
+            filter: filter.clone(),
+            start,
+            end,
+            timezone,
+        }
+    }
+
+    /// The window of June 2nd 2025 for `id`, used across tests.
+    #[cfg(test)]
+    fn test_window(id: &str) -> Self {
+        let date = NaiveDate::from_ymd_opt(2025, 6, 2).unwrap();
+        Self::covering(id, &SpanFilter::default(), date, date, None)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -120,6 +194,18 @@ struct SvgParams {
     ranges: String,
     labels: bool,
     year: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    days: Option<String>,
+    period: Option<String>,
+    project: Option<String>,
+    language: Option<String>,
+    editor: Option<String>,
+    aggregate: String,
+    compare: Option<String>,
+    week_start: String,
+    day_start: String,
+    view: String,
 }
 
 impl Default for SvgParams {
@@ -134,42 +220,80 @@ impl Default for SvgParams {
             ranges: "70,30,10".to_string(),
             labels: false,
             year: None,
+            start: None,
+            end: None,
+            days: None,
+            period: None,
+            project: None,
+            language: None,
+            editor: None,
+            aggregate: "sum".to_string(),
+            compare: None,
+            week_start: "sunday".to_string(),
+            day_start: "00:00".to_string(),
+            view: "calendar".to_string(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RequestData {
-    #[serde(default)]
-    spans: Vec<Span>,
-    #[serde(default)]
-    error: String,
-}
-
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Span {
     start_time: f64,
     end_time: f64,
     duration: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    editor: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum FetchUserSpansError {
     DisabledPublicStats,
+    NotFound,
+    Unauthorized,
     Fetch,
     Parse,
-    Api,
+    Api(String),
+    Timeout,
+    /// Carries how long the upstream asked us to wait, if known.
+    RateLimited(Option<Duration>),
+    CircuitOpen,
 }
 
 impl FetchUserSpansError {
-    fn message(self) -> &'static str {
+    fn message(&self) -> String {
         match self {
-            Self::DisabledPublicStats => "User has disabled public stats",
-            Self::Fetch => "Failed to fetch data",
-            Self::Parse => "Failed to parse response",
-            Self::Api => "Upstream API error",
+            Self::DisabledPublicStats => "User has disabled public stats".to_string(),
+            Self::NotFound => "User not found".to_string(),
+            Self::Unauthorized => "Upstream rejected our credentials".to_string(),
+            Self::Fetch => "Failed to fetch data".to_string(),
+            Self::Parse => "Failed to parse response".to_string(),
+            Self::Api(message) => format!("Upstream API error: {}", message),
+            Self::Timeout => "Upstream request timed out".to_string(),
+            Self::RateLimited(_) => "Upstream rate limit exceeded".to_string(),
+            Self::CircuitOpen => "Upstream temporarily unavailable".to_string(),
         }
     }
+
+    /// Whether the upstream answered the request, i.e. the error is not a sign of an outage.
+    fn is_answer(&self) -> bool {
+        matches!(
+            self,
+            Self::DisabledPublicStats | Self::NotFound | Self::Unauthorized | Self::Api(_)
+        )
+    }
+
+    /// Whether the upstream is refusing requests for now, in which case cached data is preferable.
+    fn is_unavailable(&self) -> bool {
+        matches!(self, Self::RateLimited(_) | Self::CircuitOpen)
+    }
+
+    fn is_retryable(&self) -> bool {
+        matches!(self, Self::Fetch | Self::Timeout)
+    }
 }
 
 type DayBuckets = HashMap<NaiveDate, u32>;
@@ -183,49 +307,54 @@ fn embed_page(svg: &str, standalone: bool) -> String {
     TEMPLATE.replace("{{SVG_CONTENT}}", svg)
 }
 
-async fn fetch_user_spans(user_range: &UserDateRange) -> Result<Vec<Span>, FetchUserSpansError> {
+async fn fetch_user_spans(
+    state: &AppState,
+    user_range: &UserDateRange,
+) -> Result<Activity, FetchUserSpansError> {
     counter!("heatmap_cache_misses_total", "cache" => "request").increment(1);
+    let activity = state.source.fetch_activity(&state.http, user_range).await?;
+    Ok(activity
+        .filtered(&user_range.filter)
+        .sanitized(state.min_span_duration))
+}
 
-    let fetch_start = Instant::now();
-    let url = format!(
-        "https://hackatime.hackclub.com/api/v1/users/{}/heartbeats/spans?start_date={}&end_date={}",
-        user_range.id, user_range.start, user_range.end
-    );
-    let resp = reqwest::get(&url).await.map_err(|err| {
-        tracing::error!("Error fetching data: {:?}", err);
-        counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
-        FetchUserSpansError::Fetch
-    })?;
-
-    let json_resp = resp.json::<RequestData>().await.map_err(|err| {
-        tracing::error!("Error parsing JSON: {:?}", err);
-        counter!("heatmap_upstream_errors_total", "type" => "parse").increment(1);
-        FetchUserSpansError::Parse
-    })?;
+/// Places dates in calendar weeks: each column is a week starting on `week_start` and each row
+/// a weekday, leaving the cells before the first and after the last date empty.
+struct Grid {
+    dates: Vec<NaiveDate>,
+    week_start: Weekday,
+    leading: usize,
+    columns: usize,
+}
 
-    match json_resp.error.as_str() {
-        "user has disabled public stats" => {
-            tracing::warn!("User {} has disabled public stats", user_range.id);
-            counter!("heatmap_upstream_errors_total", "type" => "privacy").increment(1);
-            return Err(FetchUserSpansError::DisabledPublicStats);
-        }
-        "" => {} // no error, continue
-        other => {
-            tracing::error!("Upstream API error: {}", other);
-            counter!("heatmap_upstream_errors_total", "type" => "api").increment(1);
-            return Err(FetchUserSpansError::Api);
+impl Grid {
+    fn new(dates: Vec<NaiveDate>, week_start: Weekday) -> Self {
+        let leading = dates
+            .first()
+            .map_or(0, |date| date.weekday().days_since(week_start) as usize);
+        let columns = (leading + dates.len()).div_ceil(DAYS_PER_WEEK).max(1);
+        Self {
+            dates,
+            week_start,
+            leading,
+            columns,
         }
     }
 
-    histogram!("heatmap_upstream_request_duration_seconds")
-        .record(fetch_start.elapsed().as_secs_f64());
-    Ok(json_resp.spans)
+    /// Column and row of the date at `index` in `dates`.
+    fn position(&self, index: usize) -> (usize, usize) {
+        let cell = self.leading + index;
+        (cell / DAYS_PER_WEEK, cell % DAYS_PER_WEEK)
+    }
 }
 
 fn create_svg_document(
-    all_dates: &[NaiveDate],
+    grid: &Grid,
     day_buckets: &DayBuckets,
+    baseline: Option<&DayBuckets>,
     ranges: &[u32],
+    aggregation: Aggregation,
+    unit: Unit,
     params: &SvgParams,
 ) -> String {
     let cell_size = params.cell_size;
@@ -236,49 +365,77 @@ fn create_svg_document(
     let month_height = if show_labels { MONTH_LABEL_HEIGHT } else { 0 };
     let legend_height = if show_labels { LEGEND_HEIGHT } else { 0 };
 
-    let grid_width = DEFAULT_COLS * (cell_size + padding);
-    let grid_height = DEFAULT_ROWS * (cell_size + padding);
-    let total_width = weekday_width + grid_width + if show_labels { 3 } else { 0 };
+    let grid_width = grid.columns * (cell_size + padding);
+    let grid_height = DAYS_PER_WEEK * (cell_size + padding);
     let total_height = month_height + grid_height + legend_height;
 
-    let mut document = Document::new()
-        .set("width", total_width)
-        .set("height", total_height)
-        .set("viewBox", format!("0 0 {} {}", total_width, total_height));
-
-    let mut values: Vec<u32> = all_dates
+    let mut values: Vec<u32> = grid
+        .dates
         .iter()
-        .map(|date| *day_buckets.get(date).unwrap_or(&0))
+        .map(|date| {
+            let seconds = *day_buckets.get(date).unwrap_or(&0);
+            match baseline {
+                // colors are scaled by the largest difference when comparing
+                Some(baseline) => seconds.abs_diff(*baseline.get(date).unwrap_or(&0)),
+                None => seconds,
+            }
+        })
         .collect();
     values.sort_unstable();
     let max_duration = *values.last().unwrap_or(&0);
 
     let selected_palette = get_palette(PALETTES, &params.theme);
+    let comparison = baseline.map(|baseline| {
+        (
+            baseline,
+            get_diverging_palette(DIVERGING_PALETTES, &params.theme),
+        )
+    });
     let text_color = selected_palette.text_color();
     let text_color_str = format_color(text_color.0, text_color.1, text_color.2);
 
+    let legend_colors = match comparison {
+        Some((_, diverging_palette)) => diverging_palette.all_colors(),
+        None => selected_palette.all_colors().to_vec(),
+    };
+    // short ranges are narrower than the legend, which then sets the width
+    let content_width = if show_labels {
+        (weekday_width + grid_width).max(legend_width(legend_colors.len(), cell_size))
+    } else {
+        grid_width
+    };
+    let total_width = content_width + if show_labels { 3 } else { 0 };
+
+    let mut document = Document::new()
+        .set("width", total_width)
+        .set("height", total_height)
+        .set("viewBox", format!("0 0 {} {}", total_width, total_height));
+
     if show_labels {
-        let month_group = create_month_labels(
-            all_dates,
+        let month_group =
+            create_month_labels(grid, &text_color_str, cell_size, padding, weekday_width);
+        document = document.add(month_group);
+
+        let weekday_group = create_weekday_labels(
+            grid.week_start,
             &text_color_str,
             cell_size,
             padding,
-            weekday_width,
+            month_height,
         );
-        document = document.add(month_group);
-
-        let weekday_group =
-            create_weekday_labels(&text_color_str, cell_size, padding, month_height);
         document = document.add(weekday_group);
     }
 
-    for (i, date) in all_dates.iter().enumerate() {
+    for (i, date) in grid.dates.iter().enumerate() {
         let rect = create_cell_rectangle(
-            i,
+            grid.position(i),
             date,
             day_buckets,
+            comparison,
             max_duration,
             ranges,
+            aggregation,
+            unit,
             selected_palette,
             params,
             weekday_width,
@@ -289,11 +446,11 @@ fn create_svg_document(
 
     if show_labels {
         let legend_group = create_legend(
-            selected_palette,
+            &legend_colors,
             &text_color_str,
+            content_width,
             cell_size,
             padding,
-            weekday_width,
             month_height,
         );
         document = document.add(legend_group);
@@ -302,8 +459,9 @@ fn create_svg_document(
     document.to_string()
 }
 
+/// Labels each month above its first full week.
 fn create_month_labels(
-    all_dates: &[NaiveDate],
+    grid: &Grid,
     text_color: &str,
     cell_size: usize,
     padding: usize,
@@ -312,9 +470,8 @@ fn create_month_labels(
     let mut group = Group::new();
     let mut last_month: Option<u32> = None;
 
-    for (i, date) in all_dates.iter().enumerate() {
-        let col = i / DEFAULT_ROWS;
-        let row = i % DEFAULT_ROWS;
+    for (i, date) in grid.dates.iter().enumerate() {
+        let (col, row) = grid.position(i);
 
         if row == 0 {
             let month = date.month();
@@ -335,86 +492,16 @@ fn create_month_labels(
     group
 }
 
-fn create_weekday_labels(
-    text_color: &str,
-    cell_size: usize,
-    padding: usize,
-    month_height: usize,
-) -> Group {
-    let mut group = Group::new();
-
-    for (row, label) in WEEKDAY_LABELS {
-        let y = month_height + row * (cell_size + padding) + cell_size;
-        let text = Text::new(label)
-            .set("x", 0)
-            .set("y", y)
-            .set("fill", text_color)
-            .set("font-size", "10px")
-            .set("font-family", "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif");
-        group = group.add(text);
-    }
-    group
-}
-
-fn create_legend(
-    palette: &palette::Palette,
-    text_color: &str,
-    cell_size: usize,
-    padding: usize,
-    weekday_width: usize,
-    month_height: usize,
-) -> Group {
-    let mut group = Group::new();
-    let legend_y = month_height + DEFAULT_ROWS * (cell_size + padding) + 8;
-    let legend_start_x = weekday_width + DEFAULT_COLS * (cell_size + padding) - 120;
-
-    let less_text = Text::new("Less")
-        .set("x", legend_start_x)
-        .set("y", legend_y + 9)
-        .set("fill", text_color)
-        .set("font-size", "10px")
-        .set(
-            "font-family",
-            "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif",
-        );
-    group = group.add(less_text);
-
-    let colors = palette.all_colors();
-    let box_start_x = legend_start_x + 28;
-    for (i, color) in colors.iter().enumerate() {
-        let color_str = format_color(color.0, color.1, color.2);
-        let rect = Rectangle::new()
-            .set("x", box_start_x + i * (cell_size + 2))
-            .set("y", legend_y)
-            .set("width", cell_size)
-            .set("height", cell_size)
-            .set("fill", color_str)
-            .set("rx", 2)
-            .set("ry", 2);
-        group = group.add(rect);
-    }
-
-    let more_text = Text::new("More")
-        .set("x", box_start_x + 5 * (cell_size + 2) + 2)
-        .set("y", legend_y + 9)
-        .set("fill", text_color)
-        .set("font-size", "10px")
-        .set(
-            "font-family",
-            "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif",
-        );
-    group = group.add(more_text);
-
-    group
-}
-
 #[allow(clippy::too_many_arguments)]
 fn create_cell_rectangle(
-    index: usize,
+    (col, row): (usize, usize),
     date: &NaiveDate,
     day_buckets: &DayBuckets,
+    comparison: Option<(&DayBuckets, &palette::DivergingPalette)>,
     max_duration: u32,
     ranges: &[u32],
+    aggregation: Aggregation,
+    unit: Unit,
     palette: &palette::Palette,
     params: &SvgParams,
     weekday_width: usize,
@@ -425,16 +512,30 @@ fn create_cell_rectangle(
     let radius = (params.rounding.min(100) as f32 / 200.0) * cell_size as f32;
 
     let seconds = *day_buckets.get(date).unwrap_or(&0);
-    let col = index / DEFAULT_ROWS;
-    let row = index % DEFAULT_ROWS;
     let x = weekday_width + col * (cell_size + padding);
     let y = month_height + row * (cell_size + padding);
 
-    let color = palette.calculate_color(seconds, max_duration, ranges);
+    let (color, label) = match comparison {
+        Some((baseline, diverging_palette)) => {
+            let baseline_seconds = *baseline.get(date).unwrap_or(&0);
+            let delta = seconds as i64 - baseline_seconds as i64;
+            (
+                diverging_palette.calculate_color(
+                    delta,
+                    max_duration as u64,
+                    aggregation.min_value(unit),
+                    ranges,
+                ),
+                format_comparison_label(date, seconds, baseline_seconds, aggregation, unit),
+            )
+        }
+        None => (
+            palette.calculate_color(seconds, max_duration, aggregation.min_value(unit), ranges),
+            format_cell_label(date, seconds, aggregation, unit),
+        ),
+    };
     let color_str = format_color(color.0, color.1, color.2);
 
-    let label = format_cell_label(date, seconds);
-
     let rect = Rectangle::new()
         .set("x", x)
         .set("y", y)
@@ -455,6 +556,464 @@ fn is_slack_user_agent(headers: &AxumHeaderMap) -> bool {
         .is_some_and(|ua| ua.contains("Slackbot") || ua.contains("Slack-ImgProxy"))
 }
 
+struct RenderError {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl RenderError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    fn into_response(self, request_start: Instant) -> Response {
+        counter!("heatmap_http_requests_errors_total", "error" => self.error).increment(1);
+        histogram!("heatmap_http_request_duration_seconds", "status" => self.status.as_str().to_string())
+            .record(request_start.elapsed().as_secs_f64());
+        let mut response = (self.status, self.message).into_response();
+        if let Some(retry_after) = self.retry_after {
+            // round up so clients never come back too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        response
+    }
+}
+
+impl From<FetchUserSpansError> for RenderError {
+    fn from(err: FetchUserSpansError) -> Self {
+        let (status, error) = match err {
+            FetchUserSpansError::DisabledPublicStats => {
+                (StatusCode::FORBIDDEN, "disabled_public_stats")
+            }
+            FetchUserSpansError::NotFound => (StatusCode::NOT_FOUND, "user_not_found"),
+            FetchUserSpansError::Unauthorized => (StatusCode::BAD_GATEWAY, "upstream_unauthorized"),
+            FetchUserSpansError::Fetch | FetchUserSpansError::Parse => {
+                (StatusCode::BAD_GATEWAY, "upstream_failure")
+            }
+            FetchUserSpansError::Api(_) => (StatusCode::BAD_GATEWAY, "upstream_api_error"),
+            FetchUserSpansError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "upstream_timeout"),
+            FetchUserSpansError::RateLimited(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream_rate_limited")
+            }
+            FetchUserSpansError::CircuitOpen => {
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream_circuit_open")
+            }
+        };
+        let mut render_error = Self::new(status, error, err.message());
+        if let FetchUserSpansError::RateLimited(retry_after) = err {
+            render_error.retry_after = retry_after;
+        }
+        render_error
+    }
+}
+
+async fn fetch_activity(
+    state: &AppState,
+    window: &UserDateRange,
+) -> Result<Activity, FetchUserSpansError> {
+    let (result, coalesced) = state
+        .in_flight
+        .run(window.clone(), || async {
+            let activity = fetch_user_spans(state, window).await?;
+            state.request_cache.insert(window.clone(), activity.clone());
+            if let Some(disk_cache) = &state.disk_cache {
+                disk_cache.store(window, &activity).await;
+            }
+            Ok(activity)
+        })
+        .await;
+    if coalesced {
+        counter!("heatmap_upstream_coalesced_total").increment(1);
+    }
+    result
+}
+
+async fn load_activity(
+    state: &AppState,
+    window: &UserDateRange,
+    allow_stale: bool,
+) -> Result<Activity, FetchUserSpansError> {
+    let mut lookup = state.request_cache.get(window);
+    if let (Lookup::Miss, Some(disk_cache)) = (&lookup, &state.disk_cache)
+        && let Some((activity, age)) = disk_cache.load(window).await
+    {
+        lookup = state.request_cache.restore(window.clone(), activity, age);
+    }
+
+    let stale = match lookup {
+        Lookup::Fresh(activity) => {
+            counter!("heatmap_cache_hits_total", "cache" => "request").increment(1);
+            return Ok(activity);
+        }
+        Lookup::Stale(activity) if allow_stale => {
+            counter!("heatmap_cache_hits_total", "cache" => "request").increment(1);
+            if let Some(guard) = state.request_cache.begin_refresh(window) {
+                let state = state.clone();
+                let window = window.clone();
+                tokio::spawn(async move {
+                    let _guard = guard;
+                    // bounded like a request, so a hung upstream cannot pin the refresh
+                    let refresh = fetch_activity(&state, &window);
+                    let result = tokio::time::timeout(DEFAULT_REQUEST_TIMEOUT, refresh)
+                        .await
+                        .unwrap_or(Err(FetchUserSpansError::Timeout));
+                    if let Err(err) = result {
+                        tracing::warn!(
+                            "Background refresh for {} failed: {}",
+                            window.id,
+                            err.message()
+                        );
+                    }
+                });
+            }
+            return Ok(activity);
+        }
+        Lookup::Stale(activity) => Some(activity),
+        Lookup::Miss => None,
+    };
+
+    match (fetch_activity(state, window).await, stale) {
+        (Err(err), Some(activity)) if err.is_unavailable() => {
+            tracing::warn!(
+                "Serving stale activity for {}: {}",
+                window.id,
+                err.message()
+            );
+            counter!("heatmap_cache_hits_total", "cache" => "request").increment(1);
+            Ok(activity)
+        }
+        (result, _) => result,
+    }
+}
+
+/// Buckets the activity of `id` between the local dates `start_date` and `end_date`,
+/// only loading the days that are not already finalized in the history store.
+async fn load_day_buckets(
+    state: &AppState,
+    id: &str,
+    filter: &SpanFilter,
+    calendar: &Calendar,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    allow_stale: bool,
+) -> Result<DayBuckets, FetchUserSpansError> {
+    let today = calendar.today();
+    let key = HistoryKey {
+        id: id.to_string(),
+        calendar: *calendar,
+        filter: filter.clone(),
+    };
+
+    let base = match state.history.plan(&key, start_date, end_date) {
+        Plan::Complete(day_buckets) => {
+            counter!("heatmap_incremental_fetches_total", "kind" => "none").increment(1);
+            return Ok(day_buckets);
+        }
+        Plan::Partial(history) => {
+            counter!("heatmap_incremental_fetches_total", "kind" => "partial").increment(1);
+            Some(history)
+        }
+        Plan::Full => {
+            counter!("heatmap_incremental_fetches_total", "kind" => "full").increment(1);
+            None
+        }
+    };
+
+    // start one day early so spans crossing into the open window are not cut off
+    let fetch_from = base
+        .as_ref()
+        .map_or(start_date, |history| history.finalized_through());
+    let timezone = state.source.daily_totals().then_some(calendar.tz);
+    let window = UserDateRange::covering(id, filter, fetch_from, end_date, timezone);
+    let activity = load_activity(state, &window, allow_stale).await?;
+
+    Ok(state.history.merge(
+        &key,
+        start_date,
+        end_date,
+        today,
+        base.as_deref(),
+        activity.into_day_buckets(calendar),
+    ))
+}
+
+/// Like `load_day_buckets`, but days before the cutoff of an imported export are taken from
+/// the import. Imports only hold daily totals, so they are not used when filtering.
+async fn load_member_days(
+    state: &AppState,
+    id: &str,
+    filter: &SpanFilter,
+    calendar: &Calendar,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    allow_stale: bool,
+) -> Result<DayBuckets, FetchUserSpansError> {
+    let imported = match &state.imports {
+        Some(imports) if filter.is_empty() => imports.load(id).await,
+        _ => None,
+    };
+    let Some(imported) = imported.filter(|imported| imported.cutoff > start_date) else {
+        return load_day_buckets(
+            state,
+            id,
+            filter,
+            calendar,
+            start_date,
+            end_date,
+            allow_stale,
+        )
+        .await;
+    };
+
+    let mut day_buckets = imported.days_between(start_date, end_date);
+    if imported.cutoff <= end_date {
+        day_buckets.extend(
+            load_day_buckets(
+                state,
+                id,
+                filter,
+                calendar,
+                imported.cutoff,
+                end_date,
+                allow_stale,
+            )
+            .await?,
+        );
+    }
+    Ok(day_buckets)
+}
+
+/// Buckets the spans of `id` between the local dates `start_date` and `end_date` by weekday
+/// and hour. Neither the history store nor imports keep hours, so the spans are always loaded.
+async fn load_member_hours(
+    state: &AppState,
+    id: &str,
+    filter: &SpanFilter,
+    calendar: &Calendar,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    allow_stale: bool,
+) -> Result<HourBuckets, FetchUserSpansError> {
+    let window = UserDateRange::covering(id, filter, start_date, end_date, None);
+    match load_activity(state, &window, allow_stale).await? {
+        Activity::Spans(spans) => Ok(bucket_by_hour(&spans, calendar, start_date, end_date)),
+        // sources reporting daily totals are rejected before loading
+        Activity::Days(_) => Ok(HourBuckets::new()),
+    }
+}
+
+/// Loads the buckets of every member concurrently with `load`, naming the member that failed.
+async fn load_members<'a, T, F>(
+    members: &'a [String],
+    load: impl Fn(&'a str) -> F,
+) -> Result<Vec<T>, RenderError>
+where
+    F: Future<Output = Result<T, FetchUserSpansError>>,
+{
+    let results = join_all(members.iter().map(|member| load(member))).await;
+
+    let mut member_buckets = Vec::with_capacity(results.len());
+    for (member, result) in members.iter().zip(results) {
+        match result {
+            Ok(buckets) => member_buckets.push(buckets),
+            // name the member, otherwise a team error is impossible to act on
+            Err(err) if members.len() > 1 => {
+                let mut render_error = RenderError::from(err);
+                render_error.message = format!("Team member {}: {}", member, render_error.message);
+                return Err(render_error);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(member_buckets)
+}
+
+/// Renders the heatmap for `params` and stores it in the response cache.
+/// Stale day buckets are only accepted when `allow_stale` is set.
+async fn render_heatmap(
+    state: &AppState,
+    id: &str,
+    params: &SvgParams,
+    allow_stale: bool,
+) -> Result<String, RenderError> {
+    let ranges = validate_ranges(&params.ranges)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_ranges", err))?;
+    let aggregation = Aggregation::parse(&params.aggregate)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_aggregate", err))?;
+    let week_start = parse_week_start(&params.week_start)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_week_start", err))?;
+    let view = View::parse(&params.view)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_view", err))?;
+    let members = state
+        .teams
+        .members(id)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_team", err))?;
+    let baseline = match params
+        .compare
+        .as_deref()
+        .filter(|compare| !compare.trim().is_empty())
+        .map(Baseline::parse)
+    {
+        Some(Baseline::Members(spec)) => {
+            let baseline_members = state
+                .teams
+                .members(&spec)
+                .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_compare", err))?;
+            Some((Baseline::Members(spec), baseline_members))
+        }
+        Some(Baseline::LastYear) => Some((Baseline::LastYear, members.clone())),
+        None => None,
+    };
+
+    let tz: Tz = match params.timezone.parse() {
+        Ok(tz) => tz,
+        Err(_) => {
+            tracing::warn!("Unsupported timezone: {}", params.timezone);
+            return Err(RenderError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_timezone",
+                "Unsupported timezone",
+            ));
+        }
+    };
+
+    let day_start = parse_day_start(&params.day_start)
+        .map_err(|err| RenderError::new(StatusCode::BAD_REQUEST, "invalid_day_start", err))?;
+    if day_start != NaiveTime::MIN && state.source.daily_totals() {
+        return Err(RenderError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_day_start",
+            format!(
+                "day_start is not supported by the {} source, which reports daily totals",
+                state.source.name()
+            ),
+        ));
+    }
+    if view == View::Punchcard {
+        if state.source.daily_totals() {
+            return Err(RenderError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_view",
+                format!(
+                    "The punch card is not supported by the {} source, which reports daily totals",
+                    state.source.name()
+                ),
+            ));
+        }
+        if baseline.is_some() {
+            return Err(RenderError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_view",
+                "compare is not supported by the punch card",
+            ));
+        }
+        // the columns are whole hours, so the day has to roll over on one
+        if day_start.minute() != 0 {
+            return Err(RenderError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_day_start",
+                "The punch card needs a day_start on the hour",
+            ));
+        }
+    }
+    let calendar = Calendar { tz, day_start };
+
+    let today = calendar.today();
+    let (start_date, end_date) = period::resolve(params, today, state.max_range_days)
+        .map_err(|(error, message)| RenderError::new(StatusCode::BAD_REQUEST, error, message))?;
+
+    let filter = SpanFilter::new(
+        params.project.as_deref(),
+        params.language.as_deref(),
+        params.editor.as_deref(),
+    );
+
+    if view == View::Punchcard {
+        let member_hours = load_members(&members, |member| {
+            load_member_hours(
+                state,
+                member,
+                &filter,
+                &calendar,
+                start_date,
+                end_date,
+                allow_stale,
+            )
+        })
+        .await?;
+        let svg_content = create_punchcard_document(
+            &aggregation.combine(&member_hours),
+            week_start,
+            day_start.hour(),
+            &ranges,
+            aggregation,
+            state.source.unit(),
+            params,
+        );
+        state
+            .response_cache
+            .insert(params.clone(), svg_content.clone());
+        return Ok(svg_content);
+    }
+
+    let (current, baseline_period) = try_join(
+        load_members(&members, |member| {
+            load_member_days(
+                state,
+                member,
+                &filter,
+                &calendar,
+                start_date,
+                end_date,
+                allow_stale,
+            )
+        }),
+        async {
+            let Some((baseline, baseline_members)) = &baseline else {
+                return Ok(None);
+            };
+            let (start, end) = baseline.period(start_date, end_date);
+            load_members(baseline_members, |member| {
+                load_member_days(state, member, &filter, &calendar, start, end, allow_stale)
+            })
+            .await
+            .map(Some)
+        },
+    )
+    .await?;
+    let day_buckets = aggregation.combine(&current);
+
+    let grid = Grid::new(generate_date_range(start_date, end_date), week_start);
+    let baseline_buckets = baseline
+        .zip(baseline_period)
+        .map(|((baseline, _), period)| baseline.align(&aggregation.combine(&period), &grid.dates));
+    let svg_content = create_svg_document(
+        &grid,
+        &day_buckets,
+        baseline_buckets.as_ref(),
+        &ranges,
+        aggregation,
+        state.source.unit(),
+        params,
+    );
+
+    state
+        .response_cache
+        .insert(params.clone(), svg_content.clone());
+
+    Ok(svg_content)
+}
+
 async fn make_heatmap_svg(
     State(state): State<AppState>,
     headers: AxumHeaderMap,
@@ -470,10 +1029,12 @@ async fn make_heatmap_svg(
     let id = match &params.id {
         Some(id) => id.clone(),
         None => {
-            counter!("heatmap_http_requests_errors_total", "error" => "missing_id").increment(1);
-            histogram!("heatmap_http_request_duration_seconds", "status" => "400")
-                .record(request_start.elapsed().as_secs_f64());
-            return (StatusCode::BAD_REQUEST, "Missing required parameter: id").into_response();
+            return RenderError::new(
+                StatusCode::BAD_REQUEST,
+                "missing_id",
+                "Missing required parameter: id",
+            )
+            .into_response(request_start);
         }
     };
 
@@ -493,142 +1054,39 @@ async fn make_heatmap_svg(
         "image/svg+xml"
     };
 
-    if let Some(svg_content) = state.response_cache.get(&params) {
-        counter!("heatmap_cache_hits_total", "cache" => "response").increment(1);
-        histogram!("heatmap_http_request_duration_seconds", "status" => "200")
-            .record(request_start.elapsed().as_secs_f64());
-        if output_format == OutputFormat::Png && !extra.standalone {
-            return match svg_to_png(&svg_content) {
-                Ok(png) => (StatusCode::OK, build_headers(content_type), png).into_response(),
-                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
-            };
-        }
-        let svg_buf = embed_page(&svg_content, extra.standalone);
-        return (StatusCode::OK, build_headers(content_type), svg_buf).into_response();
-    }
-
-    counter!("heatmap_cache_misses_total", "cache" => "response").increment(1);
-
-    let ranges = match validate_ranges(&params.ranges) {
-        Ok(r) => r,
-        Err(err) => {
-            counter!("heatmap_http_requests_errors_total", "error" => "invalid_ranges")
-                .increment(1);
-            histogram!("heatmap_http_request_duration_seconds", "status" => "400")
-                .record(request_start.elapsed().as_secs_f64());
-            return (StatusCode::BAD_REQUEST, err).into_response();
-        }
-    };
-
-    let tz: Tz = match params.timezone.parse() {
-        Ok(tz) => tz,
-        Err(_) => {
-            tracing::warn!("Unsupported timezone: {}", params.timezone);
-            counter!("heatmap_http_requests_errors_total", "error" => "invalid_timezone")
-                .increment(1);
-            histogram!("heatmap_http_request_duration_seconds", "status" => "400")
-                .record(request_start.elapsed().as_secs_f64());
-            return (StatusCode::BAD_REQUEST, "Unsupported timezone".to_string()).into_response();
+    let svg_content = match state.response_cache.get(&params) {
+        Lookup::Fresh(svg_content) => {
+            counter!("heatmap_cache_hits_total", "cache" => "response").increment(1);
+            svg_content
         }
-    };
-
-    let now_utc = chrono::Utc::now();
-    let now_local = now_utc.with_timezone(&tz);
-    let today = now_local.date_naive();
-    let current_year = today.year();
-
-    let (start_date, end_date) = match &params.year {
-        Some(year_str) => {
-            let year = if year_str.eq_ignore_ascii_case("current") {
-                current_year
-            } else {
-                match year_str.parse::<i32>() {
-                    Ok(y) => y,
-                    Err(_) => {
-                        counter!("heatmap_http_requests_errors_total", "error" => "invalid_year")
-                            .increment(1);
-                        histogram!("heatmap_http_request_duration_seconds", "status" => "400")
-                            .record(request_start.elapsed().as_secs_f64());
-                        return (StatusCode::BAD_REQUEST, "Invalid year parameter").into_response();
+        Lookup::Stale(svg_content) => {
+            counter!("heatmap_cache_hits_total", "cache" => "response").increment(1);
+            if let Some(guard) = state.response_cache.begin_refresh(&params) {
+                let state = state.clone();
+                let params = params.clone();
+                tokio::spawn(async move {
+                    let _guard = guard;
+                    let refresh = render_heatmap(&state, &id, &params, false);
+                    match tokio::time::timeout(DEFAULT_REQUEST_TIMEOUT, refresh).await {
+                        Ok(Err(err)) => {
+                            tracing::warn!("Background refresh for {} failed: {}", id, err.message)
+                        }
+                        Err(_) => tracing::warn!("Background refresh for {} timed out", id),
+                        Ok(Ok(_)) => {}
                     }
-                }
-            };
-            let jan_1 = NaiveDate::from_ymd_opt(year, 1, 1).unwrap();
-            let dec_31 = NaiveDate::from_ymd_opt(year, 12, 31).unwrap();
-            (jan_1, dec_31)
-        }
-        None => {
-            let one_year_ago = (now_local - chrono::Duration::days(365)).date_naive();
-            (one_year_ago, today)
-        }
-    };
-
-    let start_time = match create_timezone_date(&tz, &start_date, 0, 0, 0) {
-        Ok(ts) => ts,
-        Err(err) => {
-            tracing::error!("Invalid start date: {}", err);
-            counter!("heatmap_http_requests_errors_total", "error" => "invalid_start_date")
-                .increment(1);
-            histogram!("heatmap_http_request_duration_seconds", "status" => "500")
-                .record(request_start.elapsed().as_secs_f64());
-            return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response();
-        }
-    };
-    let end_time = match create_timezone_date(&tz, &end_date, 23, 59, 59) {
-        Ok(ts) => ts,
-        Err(err) => {
-            tracing::error!("Invalid end date: {}", err);
-            counter!("heatmap_http_requests_errors_total", "error" => "invalid_end_date")
-                .increment(1);
-            histogram!("heatmap_http_request_duration_seconds", "status" => "500")
-                .record(request_start.elapsed().as_secs_f64());
-            return (StatusCode::INTERNAL_SERVER_ERROR, err).into_response();
+                });
+            }
+            svg_content
         }
-    };
-
-    let user_range = UserDateRange {
-        id: id.clone(),
-        start: start_time,
-        end: end_time,
-    };
-
-    let day_buckets = if let Some(cached) = state.request_cache.get(&user_range) {
-        counter!("heatmap_cache_hits_total", "cache" => "request").increment(1);
-        cached
-    } else {
-        let spans = match fetch_user_spans(&user_range).await {
-            Ok(s) => s,
-            Err(err) => {
-                let (status, status_label, error_label) = match err {
-                    FetchUserSpansError::DisabledPublicStats => {
-                        (StatusCode::FORBIDDEN, "403", "disabled_public_stats")
-                    }
-                    FetchUserSpansError::Fetch
-                    | FetchUserSpansError::Parse
-                    | FetchUserSpansError::Api => {
-                        (StatusCode::INTERNAL_SERVER_ERROR, "500", "upstream_failure")
-                    }
-                };
-                counter!("heatmap_http_requests_errors_total", "error" => error_label).increment(1);
-                histogram!("heatmap_http_request_duration_seconds", "status" => status_label)
-                    .record(request_start.elapsed().as_secs_f64());
-                return (status, err.message()).into_response();
+        Lookup::Miss => {
+            counter!("heatmap_cache_misses_total", "cache" => "response").increment(1);
+            match render_heatmap(&state, &id, &params, true).await {
+                Ok(svg_content) => svg_content,
+                Err(err) => return err.into_response(request_start),
             }
-        };
-
-        let mut day_buckets: DayBuckets = HashMap::new();
-        for span in &spans {
-            process_span_into_buckets(span, &tz, &mut day_buckets);
         }
-        state.request_cache.insert(user_range, day_buckets.clone());
-        day_buckets
     };
 
-    let all_dates = generate_date_range(start_date, end_date);
-    let svg_content = create_svg_document(&all_dates, &day_buckets, &ranges, &params);
-
-    state.response_cache.insert(params, svg_content.clone());
-
     histogram!("heatmap_http_request_duration_seconds", "status" => "200")
         .record(request_start.elapsed().as_secs_f64());
 
@@ -647,6 +1105,19 @@ async fn make_heatmap_svg(
 async fn main() {
     tracing_subscriber::fmt::init();
 
+    // the import command runs next to a live server, so it must not touch the server setup
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("import") {
+        match import::run_command(config::import_dir().as_deref(), &args[1..]) {
+            Ok(summary) => println!("{}", summary),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let metrics_enabled = std::env::var("METRICS")
         .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
         .unwrap_or(false);
@@ -661,20 +1132,150 @@ async fn main() {
         tracing::info!("Prometheus metrics available at http://localhost:9292/metrics");
     }
 
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            return;
+        }
+    };
+    if config.upstream_deadline >= DEFAULT_REQUEST_TIMEOUT {
+        tracing::error!(
+            "Invalid configuration: UPSTREAM_DEADLINE_MS must be below the request timeout of {:?}",
+            DEFAULT_REQUEST_TIMEOUT
+        );
+        return;
+    }
+
+    let http = match upstream::build_http_client(&config) {
+        Ok(http) => http,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            return;
+        }
+    };
+    tracing::info!(
+        "Upstream requests use User-Agent: {}",
+        config.upstream_user_agent
+    );
+
+    let heartbeats = match source::heartbeat_store(&config) {
+        Ok(heartbeats) => heartbeats,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            return;
+        }
+    };
+    let source = match source::from_config(&config, heartbeats.clone()) {
+        Ok(source) => source,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            return;
+        }
+    };
+    match config.activity_source {
+        SourceKind::Hackatime => tracing::info!(
+            "Using Hackatime backend at {}{}",
+            config.hackatime_base_url,
+            config.hackatime_spans_path
+        ),
+        SourceKind::Wakapi => tracing::info!(
+            "Using Wakapi backend at {}{} ({})",
+            config.wakapi_base_url,
+            config.wakapi_summaries_path,
+            if config.wakapi_api_key.is_some() {
+                "authenticated"
+            } else {
+                "anonymous"
+            }
+        ),
+        kind => tracing::info!(
+            "Using {} activity source at {}",
+            source.name(),
+            config
+                .activity_source_path
+                .as_deref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| kind.as_str().to_string())
+        ),
+    }
+
+    let ingest = heartbeats.map(|store| {
+        tracing::info!(
+            "Accepting heartbeats for {} users",
+            config.ingest_api_keys.len()
+        );
+        Ingest::new(store, config.ingest_api_keys.clone())
+    });
+
+    let disk_cache = match &config.disk_cache_dir {
+        Some(dir) => match DiskCache::new(dir.clone(), config.disk_cache_ttl) {
+            Ok(disk_cache) => {
+                tracing::info!("Persisting fetched activity to {}", dir.display());
+                let disk_cache = Arc::new(disk_cache);
+                disk_cache.spawn_sweeper();
+                Some(disk_cache)
+            }
+            Err(e) => {
+                tracing::error!("Invalid configuration: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let imports = match &config.import_dir {
+        Some(dir) => match ImportStore::new(dir.clone()) {
+            Ok(imports) => {
+                tracing::info!("Merging imported history from {}", dir.display());
+                Some(Arc::new(imports))
+            }
+            Err(e) => {
+                tracing::error!("Invalid configuration: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
     let state = AppState {
-        response_cache: Cache::builder()
-            .max_capacity(MAX_RESPONSE_CACHE_ENTRIES)
-            .time_to_live(Duration::from_secs(RESPONSE_CACHE_DURATION_SECONDS))
-            .build(),
-        request_cache: Cache::builder()
-            .max_capacity(MAX_REQUEST_CACHE_ENTRIES)
-            .time_to_live(Duration::from_secs(REQUEST_CACHE_DURATION_SECONDS))
-            .build(),
+        http,
+        source: Arc::from(source),
+        response_cache: SwrCache::new(
+            "response",
+            MAX_RESPONSE_CACHE_ENTRIES,
+            Duration::from_secs(RESPONSE_CACHE_DURATION_SECONDS),
+            config.cache_stale_grace,
+        ),
+        request_cache: SwrCache::new(
+            "request",
+            MAX_REQUEST_CACHE_ENTRIES,
+            Duration::from_secs(REQUEST_CACHE_DURATION_SECONDS),
+            config.cache_stale_grace,
+        ),
+        disk_cache,
+        teams: Arc::new(Teams::new(
+            config.team_groups.clone(),
+            config.team_max_members,
+        )),
+        history: Arc::new(HistoryStore::new(
+            config.history_max_users,
+            config.incremental_open_days,
+        )),
+        imports,
+        min_span_duration: config.min_span_duration,
+        max_range_days: config.max_range_days,
+        in_flight: Arc::new(SingleFlight::new()),
     };
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(make_heatmap_svg))
         .route("/health", get(|| async { "OK" }))
+        .with_state(state);
+    if let Some(ingest) = ingest {
+        app = app.merge(ingest.router());
+    }
+    let app = app
         .layer(CompressionLayer::new().gzip(true))
         .layer(DecompressionLayer::new().gzip(true))
         .layer(TimeoutLayer::with_status_code(
@@ -683,8 +1284,7 @@ async fn main() {
         ))
         .layer(NormalizePathLayer::trim_trailing_slash())
         .layer(CatchPanicLayer::new())
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+        .layer(CorsLayer::permissive());
 
     let listener = match tokio::net::TcpListener::bind("0.0.0.0:8282").await {
         Ok(listener) => listener,
@@ -698,3 +1298,200 @@ async fn main() {
         tracing::error!("Server error: {}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use source::FixtureSource;
+
+    use super::*;
+
+    fn state(source: Arc<dyn ActivitySource>) -> AppState {
+        AppState {
+            http: reqwest::Client::new(),
+            source,
+            response_cache: SwrCache::new("response", 100, Duration::from_secs(60), Duration::ZERO),
+            request_cache: SwrCache::new("request", 100, Duration::from_secs(60), Duration::ZERO),
+            disk_cache: None,
+            history: Arc::new(HistoryStore::new(100, 0)),
+            imports: None,
+            teams: Arc::new(Teams::new(HashMap::new(), 10)),
+            min_span_duration: Duration::ZERO,
+            max_range_days: 366,
+            in_flight: Arc::new(SingleFlight::new()),
+        }
+    }
+
+    /// Starts the mock upstream on an ephemeral port, and a state fetching from it through
+    /// the resilient Hackatime source used in production, with retries and a short deadline.
+    async fn mock_state(slow: Duration) -> AppState {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, mock::router(None, slow)).await });
+
+        let mut config = Config::from_env().unwrap();
+        config.activity_source = SourceKind::Hackatime;
+        config.hackatime_base_url = format!("http://{}", addr);
+        config.upstream_retry_base_delay = Duration::ZERO;
+        config.upstream_retry_max_delay = Duration::ZERO;
+        config.upstream_deadline = Duration::from_millis(500);
+        state(Arc::from(source::from_config(&config, None).unwrap()))
+    }
+
+    /// Serves `query` through the handler.
+    async fn respond(state: &AppState, query: &str) -> Response {
+        let uri: axum::http::Uri = format!("/?{}", query).parse().unwrap();
+        make_heatmap_svg(
+            State(state.clone()),
+            AxumHeaderMap::new(),
+            Query::try_from_uri(&uri).unwrap(),
+            Query::try_from_uri(&uri).unwrap(),
+            OriginalUri(uri),
+        )
+        .await
+    }
+
+    async fn get(state: &AppState, query: &str) -> (StatusCode, String) {
+        let response = respond(state, query).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn fixtures() -> FixtureSource {
+        // 2025-06-02 12:00 to 13:30 UTC
+        let span = Span {
+            start_time: 1_748_865_600.0,
+            end_time: 1_748_871_000.0,
+            duration: 5400.0,
+            project: None,
+            language: None,
+            editor: None,
+        };
+        FixtureSource::new(HashMap::from([
+            ("alice".to_string(), Ok(vec![span])),
+            (
+                "private".to_string(),
+                Err("user has disabled public stats".to_string()),
+            ),
+            (
+                "error".to_string(),
+                Err("Internal Server Error".to_string()),
+            ),
+        ]))
+    }
+
+    fn days_from(start: NaiveDate, count: usize) -> Vec<NaiveDate> {
+        start.iter_days().take(count).collect()
+    }
+
+    #[test]
+    fn grid_rows_follow_the_week_start() {
+        // June 1st 2025 is a Sunday, June 2nd a Monday
+        let june = |day| NaiveDate::from_ymd_opt(2025, 6, day).unwrap();
+
+        let grid = Grid::new(days_from(june(1), 8), Weekday::Sun);
+        assert_eq!(grid.leading, 0);
+        assert_eq!(grid.position(0), (0, 0));
+        assert_eq!(grid.position(1), (0, 1));
+        assert_eq!(grid.position(7), (1, 0));
+        assert_eq!(grid.columns, 2);
+
+        let grid = Grid::new(days_from(june(1), 8), Weekday::Mon);
+        assert_eq!(grid.leading, 6);
+        assert_eq!(grid.position(0), (0, 6));
+        assert_eq!(grid.position(1), (1, 0));
+        assert_eq!(grid.position(7), (1, 6));
+        assert_eq!(grid.columns, 2);
+    }
+
+    #[test]
+    fn grid_columns_cover_partial_weeks() {
+        // 366 days from a Sunday to a Monday, alone in the first and last weeks
+        let start = NaiveDate::from_ymd_opt(2023, 12, 31).unwrap();
+        let grid = Grid::new(days_from(start, 366), Weekday::Mon);
+        assert_eq!(grid.columns, 54);
+        assert_eq!(grid.position(365), (53, 0));
+    }
+
+    #[tokio::test]
+    async fn renders_the_heatmap_offline() {
+        let state = state(Arc::new(fixtures()));
+        let (status, body) = get(
+            &state,
+            "id=alice&timezone=UTC&start=2025-06-01&end=2025-06-07",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("<svg"));
+        assert!(body.contains("<title>1h 30m on June 2nd</title>"));
+        assert!(body.contains("<title>No activity on June 3rd</title>"));
+    }
+
+    #[tokio::test]
+    async fn maps_upstream_errors_to_statuses() {
+        let state = state(Arc::new(fixtures()));
+        for (id, status) in [
+            ("private", StatusCode::FORBIDDEN),
+            ("missing", StatusCode::NOT_FOUND),
+            ("error", StatusCode::BAD_GATEWAY),
+        ] {
+            let (actual, _) = get(&state, &format!("id={}&timezone=UTC", id)).await;
+            assert_eq!(actual, status, "status for {}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_from_the_mock_upstream() {
+        let state = mock_state(Duration::ZERO).await;
+        let (status, body) = get(&state, "id=alice&timezone=UTC").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("<svg"));
+    }
+
+    #[tokio::test]
+    async fn malformed_upstream_responses_are_bad_gateways() {
+        let state = mock_state(Duration::ZERO).await;
+        let (status, body) = get(&state, "id=malformed&timezone=UTC").await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body, "Failed to parse response");
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        // every other request for `flaky` fails, so the retry succeeds
+        let state = mock_state(Duration::ZERO).await;
+        let (status, _) = get(&state, "id=flaky&timezone=UTC").await;
+        assert_eq!(status, StatusCode::OK);
+
+        // `error` keeps failing until the attempts run out
+        let state = mock_state(Duration::ZERO).await;
+        let (status, body) = get(&state, "id=error&timezone=UTC").await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body, "Failed to fetch data");
+    }
+
+    #[tokio::test]
+    async fn rate_limits_pass_on_retry_after() {
+        let state = mock_state(Duration::ZERO).await;
+        let response = respond(&state, "id=ratelimited&timezone=UTC").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        // the upstream is left alone meanwhile, even for other users
+        let (status, _) = get(&state, "id=alice&timezone=UTC").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn slow_upstreams_time_out() {
+        let state = mock_state(Duration::from_secs(5)).await;
+        let (status, body) = get(&state, "id=slow-alice&timezone=UTC").await;
+
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body, "Upstream request timed out");
+    }
+}
@@ -0,0 +1,189 @@
+//! A stand-in for the Hackatime spans endpoint, served by the `mock_hackatime` binary for
+//! running the heatmap without network access, and by tests for the upstream path.
+//!
+//! Serves `<id>.json` from the fixtures directory when it exists and generated spans otherwise.
+//! A few ids simulate upstream failures, see the README.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use axum::Router;
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use chrono::{Datelike, NaiveDate, TimeDelta, Utc};
+use serde_json::json;
+
+use crate::ids::is_safe_file_id;
+
+const SLOW_PREFIX: &str = "slow-";
+const PROJECTS: [(&str, &str, &str); 4] = [
+    ("hackatime-heatmap", "Rust", "vscode"),
+    ("website", "TypeScript", "vscode"),
+    ("dotfiles", "Lua", "neovim"),
+    ("scripts", "Python", "zed"),
+];
+
+struct Mock {
+    fixtures_dir: Option<PathBuf>,
+    slow: Duration,
+    /// Every other request for `flaky` fails.
+    flaky_requests: AtomicU64,
+}
+
+async fn spans(
+    State(mock): State<Arc<Mock>>,
+    Path(id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    tracing::info!("GET spans for {} ({:?})", id, query);
+
+    let id = match id.strip_prefix(SLOW_PREFIX) {
+        Some(id) => {
+            tokio::time::sleep(mock.slow).await;
+            id.to_string()
+        }
+        None => id,
+    };
+
+    match id.as_str() {
+        "private" => {
+            return error(StatusCode::FORBIDDEN, "user has disabled public stats");
+        }
+        "missing" => return error(StatusCode::NOT_FOUND, "User not found"),
+        "unauthorized" => return error(StatusCode::UNAUTHORIZED, "Unauthorized"),
+        "error" => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+        "flaky" if mock.flaky_requests.fetch_add(1, Ordering::Relaxed) % 2 == 0 => {
+            return (StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable").into_response();
+        }
+        "ratelimited" => {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "30")],
+                "Too Many Requests",
+            )
+                .into_response();
+        }
+        "malformed" => {
+            return (
+                [(header::CONTENT_TYPE, "application/json")],
+                r#"{"spans":[{"start_time":1700000000,"#,
+            )
+                .into_response();
+        }
+        _ => {}
+    }
+
+    if let Some(dir) = &mock.fixtures_dir
+        && is_safe_file_id(&id)
+    {
+        match tokio::fs::read(dir.join(format!("{}.json", id))).await {
+            Ok(bytes) => {
+                return ([(header::CONTENT_TYPE, "application/json")], bytes).into_response();
+            }
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                tracing::error!("Error reading fixture for {}: {}", id, err);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                    .into_response();
+            }
+            Err(_) => {}
+        }
+    }
+
+    let today = Utc::now().date_naive();
+    let date_param = |name: &str| {
+        query
+            .get(name)
+            .and_then(|value| value.get(..10))
+            .and_then(|value| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok())
+    };
+    let start = date_param("start_date").unwrap_or(today - TimeDelta::days(365));
+    let end = date_param("end_date").unwrap_or(today).min(today);
+
+    let spans: Vec<_> = generate_spans(&id, start, end)
+        .into_iter()
+        .filter(|span| {
+            ["project", "language", "editor"].iter().all(|field| {
+                query.get(*field).is_none_or(|wanted| {
+                    span[*field]
+                        .as_str()
+                        .is_some_and(|value| value.eq_ignore_ascii_case(wanted))
+                })
+            })
+        })
+        .collect();
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        json!({ "spans": spans }).to_string(),
+    )
+        .into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        json!({ "error": message }).to_string(),
+    )
+        .into_response()
+}
+
+/// The same id and day always produce the same spans, with quieter weekends.
+fn generate_spans(id: &str, start: NaiveDate, end: NaiveDate) -> Vec<serde_json::Value> {
+    let mut spans = Vec::new();
+    let mut date = start;
+    while date <= end {
+        let mut seed = format!("{}|{}", id, date)
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        let weekend = date.weekday().number_from_monday() > 5;
+        let count = next() % if weekend { 2 } else { 5 };
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+        for _ in 0..count {
+            let start_time = midnight + 8 * 3600 + (next() % (12 * 3600)) as i64;
+            let duration = 600 + (next() % 5400) as i64;
+            let (project, language, editor) = PROJECTS[(next() % PROJECTS.len() as u64) as usize];
+            spans.push(json!({
+                "start_time": start_time,
+                "end_time": start_time + duration,
+                "duration": duration,
+                "project": project,
+                "language": language,
+                "editor": editor,
+            }));
+        }
+
+        date = match date.succ_opt() {
+            Some(date) => date,
+            None => break,
+        };
+    }
+    spans
+}
+
+/// Routes the spans endpoint, answering `slow-` ids after `slow`.
+pub fn router(fixtures_dir: Option<PathBuf>, slow: Duration) -> Router {
+    let mock = Mock {
+        fixtures_dir,
+        slow,
+        flaky_requests: AtomicU64::new(0),
+    };
+    Router::new()
+        .route("/api/v1/users/{id}/heartbeats/spans", get(spans))
+        .with_state(Arc::new(mock))
+}
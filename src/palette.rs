@@ -11,22 +11,13 @@ impl Palette {
         self.colors[level]
     }
 
-    pub fn calculate_color(&self, value: u32, max: u32, ranges: &[u32]) -> Color {
-        let level = if value < 60 {
-            0
-        } else {
-            let ratio = value as f32 / max as f32;
-            if ratio >= ranges[0] as f32 / 100.0 {
-                4
-            } else if ratio >= ranges[1] as f32 / 100.0 {
-                3
-            } else if ratio >= ranges[2] as f32 / 100.0 {
-                2
-            } else {
-                1
-            }
-        };
-        self.get_color(level)
+    pub fn calculate_color(&self, value: u32, max: u32, min_value: u32, ranges: &[u32]) -> Color {
+        self.get_color(calculate_level(
+            value as u64,
+            max as u64,
+            min_value as u64,
+            ranges,
+        ))
     }
 
     pub fn text_color(&self) -> Color {
@@ -38,6 +29,59 @@ impl Palette {
     }
 }
 
+/// Values below `min_value` get the empty color.
+fn calculate_level(value: u64, max: u64, min_value: u64, ranges: &[u32]) -> usize {
+    if value < min_value {
+        return 0;
+    }
+    let ratio = value as f32 / max as f32;
+    if ratio >= ranges[0] as f32 / 100.0 {
+        4
+    } else if ratio >= ranges[1] as f32 / 100.0 {
+        3
+    } else if ratio >= ranges[2] as f32 / 100.0 {
+        2
+    } else {
+        1
+    }
+}
+
+/// Colors for comparison heatmaps, diverging from `neutral` towards "less" and "more".
+pub struct DivergingPalette {
+    name: &'static str,
+    less: [Color; 4],
+    neutral: Color,
+    more: [Color; 4],
+}
+
+impl DivergingPalette {
+    pub fn calculate_color(&self, delta: i64, max: u64, min_value: u32, ranges: &[u32]) -> Color {
+        match calculate_level(delta.unsigned_abs(), max, min_value as u64, ranges) {
+            0 => self.neutral,
+            level if delta > 0 => self.more[level - 1],
+            level => self.less[level - 1],
+        }
+    }
+
+    /// From much less to much more, as shown in the legend.
+    pub fn all_colors(&self) -> Vec<Color> {
+        let mut colors: Vec<Color> = self.less.iter().rev().copied().collect();
+        colors.push(self.neutral);
+        colors.extend(self.more);
+        colors
+    }
+}
+
+pub fn get_diverging_palette<'a>(
+    palettes: &'a [DivergingPalette],
+    name: &String,
+) -> &'a DivergingPalette {
+    palettes
+        .iter()
+        .find(|p| p.name == name)
+        .unwrap_or(&DIVERGING_PALETTES[0]) // default to dark
+}
+
 pub fn get_palette<'a>(palletes: &'a [Palette], name: &String) -> &'a Palette {
     palletes
         .iter()
@@ -91,3 +135,72 @@ pub const PALETTES: &[Palette] = &[
         text_color: (205, 214, 244),
     },
 ];
+
+pub const DIVERGING_PALETTES: &[DivergingPalette] = &[
+    DivergingPalette {
+        name: "dark",
+        less: [(90, 30, 35), (140, 40, 45), (200, 60, 60), (248, 81, 73)],
+        neutral: (22, 27, 34),
+        more: [(0, 69, 41), (0, 109, 50), (38, 166, 65), (57, 211, 83)],
+    },
+    DivergingPalette {
+        name: "light",
+        less: [
+            (255, 200, 200),
+            (250, 140, 130),
+            (220, 80, 70),
+            (165, 40, 40),
+        ],
+        neutral: (235, 237, 240),
+        more: [(155, 233, 168), (64, 196, 99), (48, 161, 78), (33, 110, 57)],
+    },
+    DivergingPalette {
+        name: "catppuccin_light",
+        less: [
+            (240, 190, 200),
+            (230, 120, 140),
+            (220, 60, 90),
+            (210, 15, 57),
+        ],
+        neutral: (204, 208, 218),
+        more: [
+            (180, 220, 170),
+            (120, 190, 100),
+            (85, 170, 60),
+            (64, 160, 43),
+        ],
+    },
+    DivergingPalette {
+        name: "catppuccin_dark",
+        less: [
+            (110, 60, 80),
+            (160, 80, 100),
+            (210, 110, 130),
+            (243, 139, 168),
+        ],
+        neutral: (49, 50, 68),
+        more: [
+            (80, 110, 85),
+            (120, 170, 115),
+            (150, 205, 145),
+            (166, 227, 161),
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_below_a_minute_are_not_empty() {
+        let ranges = [70, 30, 10];
+        // three active members out of at most three
+        assert_eq!(calculate_level(3, 3, 1, &ranges), 4);
+        assert_eq!(calculate_level(1, 3, 1, &ranges), 3);
+        assert_eq!(calculate_level(0, 3, 1, &ranges), 0);
+        // seconds keep needing a full minute
+        assert_eq!(calculate_level(59, 3600, 60, &ranges), 0);
+        assert_eq!(calculate_level(3600, 3600, 60, &ranges), 4);
+    }
+}
@@ -0,0 +1,239 @@
+use chrono::{Datelike, Duration, NaiveDate};
+
+use crate::SvgParams;
+
+/// The days shown by default: the last 365 days up to today.
+const DEFAULT_DAYS: i64 = 365;
+/// No activity is tracked before this year.
+const MIN_YEAR: i32 = 1970;
+
+/// Resolves the days a heatmap covers from `year=`, `start=`/`end=`, `days=` or `period=`.
+/// Only one of them may be used, and the result may span at most `max_days` days between
+/// 1970 and the end of next year. Errors come with the error code of the parameter that was invalid.
+pub fn resolve(
+    params: &SvgParams,
+    today: NaiveDate,
+    max_days: u32,
+) -> Result<(NaiveDate, NaiveDate), (&'static str, String)> {
+    let given = [
+        params.year.is_some(),
+        non_empty(&params.start).is_some() || non_empty(&params.end).is_some(),
+        non_empty(&params.days).is_some(),
+        non_empty(&params.period).is_some(),
+    ];
+    if given.iter().filter(|given| **given).count() > 1 {
+        return Err((
+            "invalid_date_range",
+            "Only one of year, start/end, days and period can be used at a time".to_string(),
+        ));
+    }
+
+    let (start_date, end_date) = match &params.year {
+        Some(year) => resolve_year(year, today).map_err(|err| ("invalid_year", err))?,
+        None => resolve_window(params, today).map_err(|err| ("invalid_date_range", err))?,
+    };
+
+    // far away dates would overflow the date arithmetic further down
+    let earliest = NaiveDate::from_ymd_opt(MIN_YEAR, 1, 1).unwrap_or(NaiveDate::MIN);
+    let latest = NaiveDate::from_ymd_opt(today.year() + 1, 12, 31).unwrap_or(today);
+    if start_date < earliest || end_date > latest {
+        return Err(match params.year {
+            Some(_) => (
+                "invalid_year",
+                format!(
+                    "Invalid year parameter, must be between {} and {}",
+                    MIN_YEAR,
+                    latest.year()
+                ),
+            ),
+            None => (
+                "invalid_date_range",
+                format!(
+                    "Invalid date range, must be between {} and {}",
+                    earliest, latest
+                ),
+            ),
+        });
+    }
+
+    if start_date > end_date {
+        return Err((
+            "invalid_date_range",
+            "Invalid date range, start must not be after end".to_string(),
+        ));
+    }
+    if (end_date - start_date).num_days() >= max_days as i64 {
+        return Err((
+            "invalid_date_range",
+            format!("Invalid date range, can span at most {} days", max_days),
+        ));
+    }
+    Ok((start_date, end_date))
+}
+
+fn resolve_year(year: &str, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let year = if year.eq_ignore_ascii_case("current") {
+        today.year()
+    } else {
+        year.parse::<i32>()
+            .map_err(|_| "Invalid year parameter".to_string())?
+    };
+    NaiveDate::from_ymd_opt(year, 1, 1)
+        .zip(NaiveDate::from_ymd_opt(year, 12, 31))
+        .ok_or_else(|| "Invalid year parameter".to_string())
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+fn resolve_window(params: &SvgParams, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let (start, end) = (non_empty(&params.start), non_empty(&params.end));
+
+    if let Some(days) = non_empty(&params.days) {
+        let days = days
+            .parse::<u32>()
+            .ok()
+            .filter(|days| *days > 0)
+            .ok_or_else(|| "Invalid days parameter, must be a positive number".to_string())?;
+        rolling(today, days)
+    } else if let Some(period) = non_empty(&params.period) {
+        resolve_period(period, today)
+    } else if start.is_some() || end.is_some() {
+        let end_date = end
+            .map(|end| parse_date("end", end))
+            .transpose()?
+            .unwrap_or(today);
+        let start_date = match start {
+            Some(start) => parse_date("start", start)?,
+            None => end_date
+                .checked_sub_signed(Duration::days(DEFAULT_DAYS))
+                .ok_or_else(|| "Invalid date range".to_string())?,
+        };
+        Ok((start_date, end_date))
+    } else {
+        Ok((today - Duration::days(DEFAULT_DAYS), today))
+    }
+}
+
+fn parse_date(name: &str, value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid {} parameter, must be a YYYY-MM-DD date", name))
+}
+
+/// The last `days` days, up to and including today.
+fn rolling(today: NaiveDate, days: u32) -> Result<(NaiveDate, NaiveDate), String> {
+    today
+        .checked_sub_signed(Duration::days(days as i64 - 1))
+        .map(|start| (start, today))
+        .ok_or_else(|| "Invalid date range".to_string())
+}
+
+/// `last_<N>_days`, `this_month`, `ytd` or `last_quarter`.
+fn resolve_period(period: &str, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let invalid = || {
+        "Invalid period parameter, must be last_<N>_days, this_month, ytd or last_quarter"
+            .to_string()
+    };
+
+    match period.to_ascii_lowercase().as_str() {
+        "this_month" => {
+            let first = today.with_day(1).ok_or_else(invalid)?;
+            let last = first
+                .checked_add_months(chrono::Months::new(1))
+                .and_then(|next| next.pred_opt())
+                .ok_or_else(invalid)?;
+            Ok((first, last))
+        }
+        "ytd" => NaiveDate::from_ymd_opt(today.year(), 1, 1)
+            .map(|first| (first, today))
+            .ok_or_else(invalid),
+        "last_quarter" => {
+            let quarter_start_month = (today.month0() / 3) * 3 + 1;
+            let this_quarter = NaiveDate::from_ymd_opt(today.year(), quarter_start_month, 1)
+                .ok_or_else(invalid)?;
+            let start = this_quarter
+                .checked_sub_months(chrono::Months::new(3))
+                .ok_or_else(invalid)?;
+            let end = this_quarter.pred_opt().ok_or_else(invalid)?;
+            Ok((start, end))
+        }
+        period => {
+            let days = period
+                .strip_prefix("last_")
+                .and_then(|rest| rest.strip_suffix("_days"))
+                .and_then(|days| days.parse::<u32>().ok())
+                .filter(|days| *days > 0)
+                .ok_or_else(invalid)?;
+            rolling(today, days)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 6, 2).unwrap()
+    }
+
+    #[test]
+    fn far_away_dates_are_rejected() {
+        let far = SvgParams {
+            start: Some("+262142-12-31".to_string()),
+            end: Some("+262142-12-31".to_string()),
+            ..SvgParams::default()
+        };
+        assert_eq!(
+            resolve(&far, today(), 366).unwrap_err().0,
+            "invalid_date_range"
+        );
+
+        let ancient = SvgParams {
+            end: Some("-262143-01-01".to_string()),
+            ..SvgParams::default()
+        };
+        assert_eq!(
+            resolve(&ancient, today(), 366).unwrap_err().0,
+            "invalid_date_range"
+        );
+
+        for year in ["262142", "1969", "2027"] {
+            let params = SvgParams {
+                year: Some(year.to_string()),
+                ..SvgParams::default()
+            };
+            assert_eq!(
+                resolve(&params, today(), 366).unwrap_err().0,
+                "invalid_year"
+            );
+        }
+    }
+
+    #[test]
+    fn dates_until_the_end_of_next_year_are_accepted() {
+        let params = SvgParams {
+            year: Some("2026".to_string()),
+            ..SvgParams::default()
+        };
+        assert!(resolve(&params, today(), 366).is_ok());
+
+        let params = SvgParams {
+            start: Some("1970-01-01".to_string()),
+            end: Some("1970-12-31".to_string()),
+            ..SvgParams::default()
+        };
+        assert!(resolve(&params, today(), 366).is_ok());
+
+        let params = SvgParams {
+            start: Some("2026-01-01".to_string()),
+            end: Some("2026-12-31".to_string()),
+            ..SvgParams::default()
+        };
+        assert!(resolve(&params, today(), 366).is_ok());
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Weekday};
+use svg::Document;
+use svg::node::element::{Circle, Group, Text, Title};
+
+use crate::labels::{
+    LEGEND_HEIGHT, MONTH_LABEL_HEIGHT, WEEKDAY_LABEL_WIDTH, create_legend, create_weekday_labels,
+    legend_width,
+};
+use crate::palette::{PALETTES, get_palette};
+use crate::source::Unit;
+use crate::team::Aggregation;
+use crate::time::{Calendar, DAYS_PER_WEEK, weekday_row};
+use crate::utils::{format_color, format_hour_label};
+use crate::{Span, SvgParams};
+
+const HOURS: u32 = 24;
+/// Every third hour is labeled so the labels fit above small cells.
+const HOUR_LABEL_STEP: u32 = 3;
+
+/// What a request renders: the calendar heatmap or the weekday × hour punch card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    Calendar,
+    Punchcard,
+}
+
+impl View {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "calendar" => Ok(Self::Calendar),
+            "punchcard" => Ok(Self::Punchcard),
+            _ => Err("Invalid view parameter, must be calendar or punchcard".to_string()),
+        }
+    }
+}
+
+/// Seconds per weekday and local hour.
+pub type HourBuckets = HashMap<(Weekday, u32), u32>;
+
+/// Splits spans at local hour boundaries and adds up the time per weekday and hour, counting
+/// only the days `start..=end`. The weekday is the one of the day the time belongs to, so with
+/// a later `day_start` the small hours count towards the day before.
+pub fn bucket_by_hour(
+    spans: &[Span],
+    calendar: &Calendar,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> HourBuckets {
+    let mut seconds: HashMap<(Weekday, u32), f64> = HashMap::new();
+
+    for span in spans {
+        let length = span.end_time - span.start_time;
+        if length <= 0.0 || span.duration <= 0.0 {
+            continue;
+        }
+        // idle time within the span is spread evenly over its hours
+        let scale = span.duration / length;
+
+        let mut current = span.start_time;
+        while current < span.end_time {
+            let Some(instant) = DateTime::from_timestamp(current.floor() as i64, 0) else {
+                break;
+            };
+            let local = instant.with_timezone(&calendar.tz);
+            let into_hour = (local.minute() * 60 + local.second()) as f64 + current.fract();
+            let next = (current + 3600.0 - into_hour).min(span.end_time);
+
+            let day = calendar.day_of(&local);
+            if (start..=end).contains(&day) {
+                *seconds.entry((day.weekday(), local.hour())).or_insert(0.0) +=
+                    (next - current) * scale;
+            }
+            current = next;
+        }
+    }
+
+    seconds
+        .into_iter()
+        .map(|(slot, seconds)| (slot, seconds.round() as u32))
+        .collect()
+}
+
+/// Renders a 7×24 punch card: a row per weekday starting on `week_start`, a column per hour
+/// starting at the hour days start, and circles sized and colored by the time in each slot.
+pub fn create_punchcard_document(
+    hours: &HourBuckets,
+    week_start: Weekday,
+    day_start_hour: u32,
+    ranges: &[u32],
+    aggregation: Aggregation,
+    unit: Unit,
+    params: &SvgParams,
+) -> String {
+    let cell_size = params.cell_size;
+    let padding = params.padding;
+    let show_labels = params.labels;
+
+    let weekday_width = if show_labels { WEEKDAY_LABEL_WIDTH } else { 0 };
+    let hour_height = if show_labels { MONTH_LABEL_HEIGHT } else { 0 };
+    let legend_height = if show_labels { LEGEND_HEIGHT } else { 0 };
+
+    let grid_width = HOURS as usize * (cell_size + padding);
+    let grid_height = DAYS_PER_WEEK * (cell_size + padding);
+    let total_height = hour_height + grid_height + legend_height;
+
+    let max_seconds = hours.values().copied().max().unwrap_or(0);
+    let palette = get_palette(PALETTES, &params.theme);
+    let text_color = palette.text_color();
+    let text_color_str = format_color(text_color.0, text_color.1, text_color.2);
+    let legend_colors = palette.all_colors();
+
+    let content_width = if show_labels {
+        (weekday_width + grid_width).max(legend_width(legend_colors.len(), cell_size))
+    } else {
+        grid_width
+    };
+    let total_width = content_width + if show_labels { 3 } else { 0 };
+
+    let mut document = Document::new()
+        .set("width", total_width)
+        .set("height", total_height)
+        .set("viewBox", format!("0 0 {} {}", total_width, total_height));
+
+    if show_labels {
+        document = document.add(create_hour_labels(
+            &text_color_str,
+            day_start_hour,
+            cell_size,
+            padding,
+            weekday_width,
+        ));
+        document = document.add(create_weekday_labels(
+            week_start,
+            &text_color_str,
+            cell_size,
+            padding,
+            hour_height,
+        ));
+    }
+
+    let half_cell = cell_size as f32 / 2.0;
+    let weekdays = std::iter::successors(Some(week_start), |weekday| Some(weekday.succ()));
+    for weekday in weekdays.take(DAYS_PER_WEEK) {
+        for col in 0..HOURS {
+            let hour = (day_start_hour + col) % HOURS;
+            let seconds = *hours.get(&(weekday, hour)).unwrap_or(&0);
+            let color =
+                palette.calculate_color(seconds, max_seconds, aggregation.min_value(unit), ranges);
+            // empty slots keep a small dot so the grid stays readable
+            let ratio = if max_seconds > 0 {
+                (seconds as f32 / max_seconds as f32).sqrt()
+            } else {
+                0.0
+            };
+            let radius = half_cell * (0.3 + 0.7 * ratio);
+            let label = format_hour_label(weekday, hour, seconds, aggregation, unit);
+
+            let circle = Circle::new()
+                .set(
+                    "cx",
+                    weekday_width as f32
+                        + (col as usize * (cell_size + padding)) as f32
+                        + half_cell,
+                )
+                .set(
+                    "cy",
+                    hour_height as f32
+                        + (weekday_row(weekday, week_start) * (cell_size + padding)) as f32
+                        + half_cell,
+                )
+                .set("r", radius)
+                .set("fill", format_color(color.0, color.1, color.2))
+                .add(Title::new(&label));
+            document = document.add(circle);
+        }
+    }
+
+    if show_labels {
+        document = document.add(create_legend(
+            legend_colors,
+            &text_color_str,
+            content_width,
+            cell_size,
+            padding,
+            hour_height,
+        ));
+    }
+
+    document.to_string()
+}
+
+fn create_hour_labels(
+    text_color: &str,
+    day_start_hour: u32,
+    cell_size: usize,
+    padding: usize,
+    weekday_width: usize,
+) -> Group {
+    let mut group = Group::new();
+    for col in (0..HOURS).step_by(HOUR_LABEL_STEP as usize) {
+        let hour = (day_start_hour + col) % HOURS;
+        let text = Text::new(format!("{:02}", hour))
+            .set("x", weekday_width + col as usize * (cell_size + padding))
+            .set("y", 10)
+            .set("fill", text_color)
+            .set("font-size", "10px")
+            .set(
+                "font-family",
+                "-apple-system,BlinkMacSystemFont,'Segoe UI','Noto Sans',Helvetica,Arial,sans-serif",
+            );
+        group = group.add(text);
+    }
+    group
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use metrics::counter;
+
+use crate::Span;
+
+/// Repairs spans before they are bucketed: drops spans with invalid bounds, clamps durations
+/// to the span's length, merges overlapping spans so no time is counted twice and drops
+/// spans shorter than `min_duration`.
+pub fn sanitize_spans(spans: Vec<Span>, min_duration: Duration) -> Vec<Span> {
+    let mut invalid = 0;
+    let mut clamped = 0;
+    let mut merged = 0;
+
+    let mut spans: Vec<Span> = spans
+        .into_iter()
+        .filter_map(|mut span| {
+            if !span.start_time.is_finite()
+                || !span.end_time.is_finite()
+                || span.end_time < span.start_time
+            {
+                invalid += 1;
+                return None;
+            }
+            let length = span.end_time - span.start_time;
+            if span.duration.is_nan() || span.duration < 0.0 || span.duration > length {
+                clamped += 1;
+                span.duration = if span.duration.is_nan() {
+                    0.0
+                } else {
+                    span.duration.clamp(0.0, length)
+                };
+            }
+            Some(span)
+        })
+        .collect();
+    spans.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+
+    let mut result: Vec<Span> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match result.last_mut() {
+            Some(last) if span.start_time < last.end_time => {
+                merged += 1;
+                // without overlap the durations add up, with it at most the covered time counts
+                last.end_time = last.end_time.max(span.end_time);
+                last.duration =
+                    (last.duration + span.duration).min(last.end_time - last.start_time);
+            }
+            _ => result.push(span),
+        }
+    }
+
+    let min_duration = min_duration.as_secs_f64();
+    let before = result.len();
+    result.retain(|span| span.duration >= min_duration);
+    let too_short = before - result.len();
+
+    for (reason, count) in [
+        ("invalid", invalid),
+        ("clamped", clamped),
+        ("merged", merged),
+        ("too_short", too_short),
+    ] {
+        if count > 0 {
+            counter!("heatmap_spans_corrected_total", "reason" => reason).increment(count as u64);
+        }
+    }
+    if invalid + clamped + merged > 0 {
+        tracing::debug!(
+            "Corrected spans: {} invalid, {} clamped, {} merged",
+            invalid,
+            clamped,
+            merged
+        );
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start_time: f64, end_time: f64, duration: f64) -> Span {
+        Span {
+            start_time,
+            end_time,
+            duration,
+            project: None,
+            language: None,
+            editor: None,
+        }
+    }
+
+    /// `(start_time, end_time, duration)` of each span, for comparing results.
+    fn bounds(spans: &[Span]) -> Vec<(f64, f64, f64)> {
+        spans
+            .iter()
+            .map(|span| (span.start_time, span.end_time, span.duration))
+            .collect()
+    }
+
+    #[test]
+    fn durations_are_clamped_to_the_span() {
+        let spans = sanitize_spans(
+            vec![
+                span(0.0, 100.0, -5.0),
+                span(200.0, 300.0, f64::NAN),
+                span(400.0, 500.0, 250.0),
+                span(600.0, 700.0, 40.0),
+            ],
+            Duration::ZERO,
+        );
+
+        assert_eq!(
+            bounds(&spans),
+            [
+                (0.0, 100.0, 0.0),
+                (200.0, 300.0, 0.0),
+                (400.0, 500.0, 100.0),
+                (600.0, 700.0, 40.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_with_invalid_bounds_are_dropped() {
+        let spans = sanitize_spans(
+            vec![
+                span(100.0, 50.0, 50.0),
+                span(f64::NAN, 50.0, 50.0),
+                span(0.0, f64::INFINITY, 50.0),
+                span(0.0, 0.0, 0.0),
+            ],
+            Duration::ZERO,
+        );
+
+        assert_eq!(bounds(&spans), [(0.0, 0.0, 0.0)]);
+    }
+
+    #[test]
+    fn overlapping_spans_count_covered_time_once() {
+        let spans = sanitize_spans(
+            vec![
+                // sorted before merging
+                span(50.0, 150.0, 100.0),
+                span(0.0, 100.0, 100.0),
+                // fully contained in the span before it
+                span(300.0, 400.0, 100.0),
+                span(320.0, 340.0, 20.0),
+            ],
+            Duration::ZERO,
+        );
+
+        // 0..150 is covered by 200s of partially idle spans, capped at the 150s covered
+        assert_eq!(bounds(&spans), [(0.0, 150.0, 150.0), (300.0, 400.0, 100.0)]);
+    }
+
+    #[test]
+    fn overlapping_idle_spans_keep_their_durations() {
+        let spans = sanitize_spans(
+            vec![span(0.0, 100.0, 30.0), span(50.0, 150.0, 40.0)],
+            Duration::ZERO,
+        );
+
+        assert_eq!(bounds(&spans), [(0.0, 150.0, 70.0)]);
+    }
+
+    #[test]
+    fn touching_spans_are_not_merged() {
+        let spans = sanitize_spans(
+            vec![span(0.0, 100.0, 100.0), span(100.0, 200.0, 100.0)],
+            Duration::ZERO,
+        );
+
+        assert_eq!(bounds(&spans), [(0.0, 100.0, 100.0), (100.0, 200.0, 100.0)]);
+    }
+
+    #[test]
+    fn spans_shorter_than_the_minimum_are_dropped() {
+        let spans = sanitize_spans(
+            vec![
+                span(0.0, 100.0, 59.0),
+                span(200.0, 300.0, 60.0),
+                // merged into one long enough span first
+                span(400.0, 440.0, 40.0),
+                span(430.0, 470.0, 40.0),
+            ],
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(bounds(&spans), [(200.0, 300.0, 60.0), (400.0, 470.0, 70.0)]);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// Deduplicates concurrent calls for the same key so only one of them does the work.
+pub struct SingleFlight<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+/// Removes the leader's entry once it finishes, or if its request gets cancelled.
+struct LeaderGuard<'a, K: Hash + Eq, V> {
+    flights: &'a SingleFlight<K, V>,
+    key: &'a K,
+    cell: &'a Arc<OnceCell<V>>,
+}
+
+impl<K: Hash + Eq, V> Drop for LeaderGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let mut in_flight = self.flights.in_flight.lock().unwrap();
+        if in_flight
+            .get(self.key)
+            .is_some_and(|cell| Arc::ptr_eq(cell, self.cell))
+        {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SingleFlight<K, V> {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `f` unless a call for `key` is already in flight, in which case its result is shared.
+    /// The returned flag is `true` when the result came from another caller.
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> (V, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let (cell, coalesced) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(cell) => (cell.clone(), true),
+                None => {
+                    let cell = Arc::new(OnceCell::new());
+                    in_flight.insert(key.clone(), cell.clone());
+                    (cell, false)
+                }
+            }
+        };
+
+        let _guard = (!coalesced).then(|| LeaderGuard {
+            flights: self,
+            key: &key,
+            cell: &cell,
+        });
+
+        let value = cell.get_or_init(f).await.clone();
+        (value, coalesced)
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use metrics::{counter, histogram};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, SourceKind};
+use crate::git::GitSource;
+use crate::ids::is_safe_file_id;
+use crate::ingest::{HeartbeatStore, LocalSource};
+use crate::sanitize::sanitize_spans;
+use crate::time::{Calendar, human_time, process_span_into_buckets};
+use crate::upstream::ResilientSource;
+use crate::wakapi::WakapiSource;
+use crate::{DayBuckets, FetchUserSpansError, Span, UserDateRange};
+
+/// Longest `Retry-After` honoured, so a bogus header cannot stop all requests indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+pub type SourceFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Activity, FetchUserSpansError>> + Send + 'a>>;
+
+/// Activity returned by a source, either raw spans or totals already bucketed per day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activity {
+    Spans(Vec<Span>),
+    Days(DayBuckets),
+}
+
+impl Activity {
+    /// Drops spans that do not match `filter`. Daily totals can only be filtered by the source.
+    pub fn filtered(self, filter: &SpanFilter) -> Self {
+        match self {
+            Self::Spans(spans) if !filter.is_empty() => Self::Spans(
+                spans
+                    .into_iter()
+                    .filter(|span| filter.matches(span))
+                    .collect(),
+            ),
+            activity => activity,
+        }
+    }
+
+    pub fn sanitized(self, min_duration: Duration) -> Self {
+        match self {
+            Self::Spans(spans) => Self::Spans(sanitize_spans(spans, min_duration)),
+            activity => activity,
+        }
+    }
+
+    pub fn into_day_buckets(self, calendar: &Calendar) -> DayBuckets {
+        match self {
+            Self::Spans(spans) => {
+                let mut day_buckets = DayBuckets::new();
+                for span in &spans {
+                    process_span_into_buckets(span, calendar, &mut day_buckets);
+                }
+                day_buckets
+            }
+            Self::Days(day_buckets) => day_buckets,
+        }
+    }
+}
+
+/// Restricts activity to a single project, language and/or editor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SpanFilter {
+    project: Option<String>,
+    language: Option<String>,
+    editor: Option<String>,
+}
+
+impl SpanFilter {
+    pub fn new(project: Option<&str>, language: Option<&str>, editor: Option<&str>) -> Self {
+        let normalize = |value: Option<&str>| {
+            value
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            project: normalize(project),
+            language: normalize(language),
+            editor: normalize(editor),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.project.is_none() && self.language.is_none() && self.editor.is_none()
+    }
+
+    /// The set filters as query parameters, named like the WakaTime API does.
+    pub fn pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("project", &self.project),
+            ("language", &self.language),
+            ("editor", &self.editor),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+    }
+
+    /// Spans that do not carry a filtered field are dropped, as there is no telling whether
+    /// the source actually applied the filter to them.
+    pub fn matches(&self, span: &Span) -> bool {
+        let field_matches = |filter: &Option<String>, value: &Option<String>| match (filter, value)
+        {
+            (Some(filter), Some(value)) => filter.eq_ignore_ascii_case(value),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        field_matches(&self.project, &span.project)
+            && field_matches(&self.language, &span.language)
+            && field_matches(&self.editor, &span.editor)
+    }
+}
+
+/// Appends the filter to an upstream URL as query parameters.
+pub fn filtered_url(url: &str, filter: &SpanFilter) -> Result<Url, FetchUserSpansError> {
+    let mut url = Url::parse(url).map_err(|err| {
+        tracing::error!("Invalid upstream URL {}: {}", url, err);
+        counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+        FetchUserSpansError::Fetch
+    })?;
+    if !filter.is_empty() {
+        url.query_pairs_mut().extend_pairs(filter.pairs());
+    }
+    Ok(url)
+}
+
+/// What the values in the day buckets of a source count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Seconds,
+    Commits,
+}
+
+impl Unit {
+    /// The smallest value that counts as activity: a minute, or a single commit.
+    pub fn min_value(self) -> u32 {
+        match self {
+            Self::Seconds => 60,
+            Self::Commits => 1,
+        }
+    }
+
+    pub fn describe(self, value: u32) -> String {
+        match self {
+            Self::Seconds => human_time(value),
+            Self::Commits if value == 1 => "1 commit".to_string(),
+            Self::Commits => format!("{} commits", value),
+        }
+    }
+}
+
+/// A backend that can provide the coding activity of a user within a date range.
+pub trait ActivitySource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the source reports totals per local day, which depend on the timezone.
+    fn daily_totals(&self) -> bool {
+        false
+    }
+
+    fn unit(&self) -> Unit {
+        Unit::Seconds
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a>;
+}
+
+/// The heartbeat store of the local source, shared with the ingestion endpoint.
+pub fn heartbeat_store(config: &Config) -> Result<Option<Arc<HeartbeatStore>>, String> {
+    match config.activity_source {
+        SourceKind::Local => Ok(Some(Arc::new(HeartbeatStore::new(required_source_path(
+            config,
+        )?)?))),
+        _ => Ok(None),
+    }
+}
+
+pub fn from_config(
+    config: &Config,
+    heartbeats: Option<Arc<HeartbeatStore>>,
+) -> Result<Box<dyn ActivitySource>, String> {
+    match config.activity_source {
+        SourceKind::Hackatime => Ok(Box::new(ResilientSource::new(
+            HackatimeSource::new(config),
+            config,
+        ))),
+        SourceKind::Wakapi => Ok(Box::new(ResilientSource::new(
+            WakapiSource::new(config),
+            config,
+        ))),
+        SourceKind::File => {
+            let dir = required_source_path(config)?;
+            Ok(Box::new(FileSource::new(dir)))
+        }
+        SourceKind::Fixture => {
+            let path = required_source_path(config)?;
+            FixtureSource::load(&path).map(|source| Box::new(source) as Box<dyn ActivitySource>)
+        }
+        SourceKind::Local => {
+            let store = heartbeats.ok_or("The local source needs a heartbeat store")?;
+            Ok(Box::new(LocalSource::new(
+                store,
+                config.ingest_api_keys.values().cloned().collect(),
+                config.ingest_span_timeout,
+            )))
+        }
+        SourceKind::Git => {
+            let repo = required_source_path(config)?;
+            GitSource::new(repo, config).map(|source| Box::new(source) as Box<dyn ActivitySource>)
+        }
+    }
+}
+
+fn required_source_path(config: &Config) -> Result<PathBuf, String> {
+    config.activity_source_path.clone().ok_or_else(|| {
+        format!(
+            "ACTIVITY_SOURCE_PATH is required for the {} source",
+            config.activity_source.as_str()
+        )
+    })
+}
+
+pub fn map_request_error(err: reqwest::Error) -> FetchUserSpansError {
+    if err.is_timeout() {
+        tracing::error!("Upstream request timed out: {:?}", err);
+        counter!("heatmap_upstream_errors_total", "type" => "timeout").increment(1);
+        return FetchUserSpansError::Timeout;
+    }
+    tracing::error!("Error fetching data: {:?}", err);
+    counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+    FetchUserSpansError::Fetch
+}
+
+/// Maps an upstream error response to the most specific variant, falling back to the
+/// status code when the payload does not say what went wrong.
+pub fn api_error(status: StatusCode, message: &str, id: &str) -> FetchUserSpansError {
+    let normalized = message.trim().to_ascii_lowercase();
+    let (err, kind) = if normalized == "user has disabled public stats" {
+        (FetchUserSpansError::DisabledPublicStats, "privacy")
+    } else if status == StatusCode::NOT_FOUND || normalized.contains("not found") {
+        (FetchUserSpansError::NotFound, "not_found")
+    } else if status == StatusCode::UNAUTHORIZED || normalized.contains("unauthorized") {
+        (FetchUserSpansError::Unauthorized, "unauthorized")
+    } else if message.is_empty() {
+        (FetchUserSpansError::Api(status.to_string()), "api")
+    } else {
+        (FetchUserSpansError::Api(message.to_string()), "api")
+    };
+
+    match err {
+        FetchUserSpansError::DisabledPublicStats => {
+            tracing::warn!("User {} has disabled public stats", id)
+        }
+        FetchUserSpansError::NotFound => tracing::warn!("User {} not found upstream", id),
+        FetchUserSpansError::Unauthorized => {
+            tracing::error!("Upstream rejected the request for {} as unauthorized", id)
+        }
+        _ => tracing::error!("Upstream API error ({}): {}", status, message),
+    }
+    counter!("heatmap_upstream_errors_total", "type" => kind).increment(1);
+    err
+}
+
+/// Maps throttling and server errors, which carry no usable payload, before the body is parsed.
+pub fn check_status(resp: &reqwest::Response) -> Result<(), FetchUserSpansError> {
+    let status = resp.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        tracing::warn!(
+            "Upstream rate limit exceeded, retry after {:?}",
+            retry_after
+        );
+        counter!("heatmap_upstream_errors_total", "type" => "rate_limited").increment(1);
+        return Err(FetchUserSpansError::RateLimited(retry_after));
+    }
+    if status.is_server_error() {
+        tracing::error!("Upstream server error: {}", status);
+        counter!("heatmap_upstream_errors_total", "type" => "server").increment(1);
+        return Err(FetchUserSpansError::Fetch);
+    }
+    Ok(())
+}
+
+/// Parses a `Retry-After` value, given either in seconds or as an HTTP date, capped at
+/// `MAX_RETRY_AFTER`.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let retry_after = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => {
+            let retry_at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (retry_at.to_utc() - chrono::Utc::now()).to_std().ok()?
+        }
+    };
+    Some(retry_after.min(MAX_RETRY_AFTER))
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RequestData {
+    #[serde(default)]
+    spans: Vec<Span>,
+    #[serde(default)]
+    error: String,
+}
+
+impl RequestData {
+    fn into_spans(self, id: &str, status: StatusCode) -> Result<Vec<Span>, FetchUserSpansError> {
+        if self.error.is_empty() && status.is_success() {
+            return Ok(self.spans);
+        }
+        Err(api_error(status, &self.error, id))
+    }
+}
+
+#[inline(always)]
+pub fn span_in_range(span: &Span, user_range: &UserDateRange) -> bool {
+    span.end_time >= user_range.start.timestamp() as f64
+        && span.start_time <= user_range.end.timestamp() as f64
+}
+
+/// Spans served by the Hackatime (or a compatible) HTTP API.
+pub struct HackatimeSource {
+    config: Config,
+}
+
+impl HackatimeSource {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        http: &reqwest::Client,
+        user_range: &UserDateRange,
+    ) -> Result<Vec<Span>, FetchUserSpansError> {
+        let fetch_start = Instant::now();
+        let url = format!(
+            "{}?start_date={}&end_date={}",
+            self.config.spans_url(&user_range.id),
+            user_range.start,
+            user_range.end
+        );
+        let url = filtered_url(&url, &user_range.filter)?;
+        let resp = http.get(url).send().await.map_err(map_request_error)?;
+        let status = resp.status();
+        check_status(&resp)?;
+
+        let json_resp = match resp.json::<RequestData>().await {
+            Ok(json_resp) => json_resp,
+            Err(_) if !status.is_success() => return Err(api_error(status, "", &user_range.id)),
+            Err(err) => {
+                tracing::error!("Error parsing JSON: {:?}", err);
+                counter!("heatmap_upstream_errors_total", "type" => "parse").increment(1);
+                return Err(FetchUserSpansError::Parse);
+            }
+        };
+
+        let spans = json_resp.into_spans(&user_range.id, status)?;
+
+        histogram!("heatmap_upstream_request_duration_seconds")
+            .record(fetch_start.elapsed().as_secs_f64());
+        Ok(spans)
+    }
+}
+
+impl ActivitySource for HackatimeSource {
+    fn name(&self) -> &'static str {
+        "hackatime"
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move { self.fetch(http, user_range).await.map(Activity::Spans) })
+    }
+}
+
+/// Spans read from `<dir>/<id>.json` (an API response) or `<dir>/<id>.ndjson` (one span per line).
+pub struct FileSource {
+    dir: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    async fn fetch(&self, user_range: &UserDateRange) -> Result<Vec<Span>, FetchUserSpansError> {
+        let id = &user_range.id;
+        if !is_safe_file_id(id) {
+            tracing::warn!("Refusing to read spans for invalid id: {}", id);
+            return Err(FetchUserSpansError::NotFound);
+        }
+
+        let json_path = self.dir.join(format!("{}.json", id));
+        let spans = match tokio::fs::read(&json_path).await {
+            Ok(bytes) => parse_json_file(&json_path, &bytes)?.into_spans(id, StatusCode::OK)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let ndjson_path = self.dir.join(format!("{}.ndjson", id));
+                let bytes = tokio::fs::read(&ndjson_path).await.map_err(|err| {
+                    if err.kind() == std::io::ErrorKind::NotFound {
+                        return api_error(StatusCode::NOT_FOUND, "", id);
+                    }
+                    tracing::error!("Error reading {}: {}", ndjson_path.display(), err);
+                    counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+                    FetchUserSpansError::Fetch
+                })?;
+                parse_ndjson_file(&ndjson_path, &bytes)?
+            }
+            Err(err) => {
+                tracing::error!("Error reading {}: {}", json_path.display(), err);
+                counter!("heatmap_upstream_errors_total", "type" => "fetch").increment(1);
+                return Err(FetchUserSpansError::Fetch);
+            }
+        };
+
+        Ok(spans
+            .into_iter()
+            .filter(|span| span_in_range(span, user_range))
+            .collect())
+    }
+}
+
+fn parse_json_file(path: &Path, bytes: &[u8]) -> Result<RequestData, FetchUserSpansError> {
+    serde_json::from_slice(bytes).map_err(|err| {
+        tracing::error!("Error parsing {}: {}", path.display(), err);
+        counter!("heatmap_upstream_errors_total", "type" => "parse").increment(1);
+        FetchUserSpansError::Parse
+    })
+}
+
+fn parse_ndjson_file(path: &Path, bytes: &[u8]) -> Result<Vec<Span>, FetchUserSpansError> {
+    bytes
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.trim_ascii().is_empty())
+        .map(|line| {
+            serde_json::from_slice(line).map_err(|err| {
+                tracing::error!("Error parsing {}: {}", path.display(), err);
+                counter!("heatmap_upstream_errors_total", "type" => "parse").increment(1);
+                FetchUserSpansError::Parse
+            })
+        })
+        .collect()
+}
+
+impl ActivitySource for FileSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        _http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move { self.fetch(user_range).await.map(Activity::Spans) })
+    }
+}
+
+/// Fixed per-user responses kept in memory, for tests and demos.
+pub struct FixtureSource {
+    users: HashMap<String, Result<Vec<Span>, String>>,
+}
+
+impl FixtureSource {
+    pub fn new(users: HashMap<String, Result<Vec<Span>, String>>) -> Self {
+        Self { users }
+    }
+
+    /// Loads a JSON object mapping each user id to an API response.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read fixtures from {}: {}", path.display(), e))?;
+        let responses: HashMap<String, RequestData> = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Failed to parse fixtures from {}: {}", path.display(), e))?;
+
+        Ok(Self::new(
+            responses
+                .into_iter()
+                .map(|(id, data)| {
+                    let result = if data.error.is_empty() {
+                        Ok(data.spans)
+                    } else {
+                        Err(data.error)
+                    };
+                    (id, result)
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl ActivitySource for FixtureSource {
+    fn name(&self) -> &'static str {
+        "fixture"
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        _http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        let result = match self.users.get(&user_range.id) {
+            Some(Ok(spans)) => Ok(Activity::Spans(
+                spans
+                    .iter()
+                    .filter(|span| span_in_range(span, user_range))
+                    .cloned()
+                    .collect(),
+            )),
+            Some(Err(message)) => Err(api_error(StatusCode::OK, message, &user_range.id)),
+            None => Err(api_error(StatusCode::NOT_FOUND, "", &user_range.id)),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start_time: f64, end_time: f64) -> Span {
+        Span {
+            start_time,
+            end_time,
+            duration: end_time - start_time,
+            project: None,
+            language: None,
+            editor: None,
+        }
+    }
+
+    async fn fetch(source: &FixtureSource, id: &str) -> Result<Activity, FetchUserSpansError> {
+        source
+            .fetch_activity(&reqwest::Client::new(), &UserDateRange::test_window(id))
+            .await
+    }
+
+    #[test]
+    fn filters_drop_spans_without_the_filtered_field() {
+        let filter = SpanFilter::new(Some("heatmap"), None, None);
+        let tagged = Span {
+            project: Some("Heatmap".to_string()),
+            ..span(0.0, 60.0)
+        };
+
+        assert!(filter.matches(&tagged));
+        assert!(!filter.matches(&span(0.0, 60.0)));
+        assert!(SpanFilter::default().matches(&span(0.0, 60.0)));
+        let Activity::Spans(spans) =
+            Activity::Spans(vec![tagged, span(0.0, 60.0)]).filtered(&filter)
+        else {
+            panic!("expected spans");
+        };
+        assert_eq!(spans.len(), 1);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(parse_retry_after(" 30 "), Some(Duration::from_secs(30)));
+        assert_eq!(
+            parse_retry_after("10000000000000000000"),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(
+            parse_retry_after("Fri, 31 Dec 9999 23:59:59 GMT"),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn fixture_source_serves_spans_within_the_window() {
+        // 2025-06-02 12:00 UTC and a day far outside the window
+        let inside = span(1_748_865_600.0, 1_748_869_200.0);
+        let outside = span(1_700_000_000.0, 1_700_003_600.0);
+        let source = FixtureSource::new(HashMap::from([(
+            "alice".to_string(),
+            Ok(vec![inside, outside]),
+        )]));
+
+        let Ok(Activity::Spans(spans)) = fetch(&source, "alice").await else {
+            panic!("expected spans");
+        };
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].start_time, 1_748_865_600.0);
+    }
+
+    #[tokio::test]
+    async fn fixture_source_maps_errors_like_the_api() {
+        let source = FixtureSource::new(HashMap::from([(
+            "private".to_string(),
+            Err("user has disabled public stats".to_string()),
+        )]));
+
+        assert!(matches!(
+            fetch(&source, "private").await,
+            Err(FetchUserSpansError::DisabledPublicStats)
+        ));
+        assert!(matches!(
+            fetch(&source, "missing").await,
+            Err(FetchUserSpansError::NotFound)
+        ));
+    }
+}
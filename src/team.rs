@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::source::Unit;
+
+const GROUP_PREFIX: char = '@';
+
+/// How the buckets of several members are combined into one heatmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Sum,
+    Average,
+    Active,
+}
+
+impl Aggregation {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "sum" => Ok(Self::Sum),
+            "average" | "avg" => Ok(Self::Average),
+            "active" => Ok(Self::Active),
+            _ => Err("Invalid aggregate parameter, must be sum, average or active".to_string()),
+        }
+    }
+
+    /// Describes a cell value in the unit of the source, unless active members are counted.
+    pub fn describe(self, value: u32, unit: Unit) -> String {
+        match self {
+            Self::Sum | Self::Average => unit.describe(value),
+            Self::Active if value == 1 => "1 active member".to_string(),
+            Self::Active => format!("{} active members", value),
+        }
+    }
+
+    /// The smallest combined value that is not drawn as empty, in the unit of the source
+    /// unless active members are counted.
+    pub fn min_value(self, unit: Unit) -> u32 {
+        match self {
+            Self::Sum | Self::Average => unit.min_value(),
+            Self::Active => 1,
+        }
+    }
+
+    /// Combines per-member buckets, keyed by day or by any other slot.
+    pub fn combine<K: Copy + Eq + Hash>(self, members: &[HashMap<K, u32>]) -> HashMap<K, u32> {
+        let mut combined: HashMap<K, u32> = HashMap::new();
+        for buckets in members {
+            for (key, seconds) in buckets {
+                let value = match self {
+                    Self::Sum | Self::Average => *seconds,
+                    Self::Active => u32::from(*seconds > 0),
+                };
+                let entry = combined.entry(*key).or_insert(0);
+                *entry = entry.saturating_add(value);
+            }
+        }
+        if self == Self::Average && !members.is_empty() {
+            for seconds in combined.values_mut() {
+                *seconds = (*seconds as f64 / members.len() as f64).round() as u32;
+            }
+        }
+        combined
+    }
+}
+
+/// Resolves `id=` into the users it names: a single id, a comma-separated list,
+/// or `@name` for a group defined in `TEAM_GROUPS`.
+pub struct Teams {
+    groups: HashMap<String, Vec<String>>,
+    max_members: usize,
+}
+
+impl Teams {
+    pub fn new(groups: HashMap<String, Vec<String>>, max_members: usize) -> Self {
+        Self {
+            groups,
+            max_members,
+        }
+    }
+
+    pub fn members(&self, id: &str) -> Result<Vec<String>, String> {
+        let members = match id.strip_prefix(GROUP_PREFIX) {
+            Some(name) => self
+                .groups
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown team '{}'", name))?,
+            None => parse_member_list(id),
+        };
+
+        if members.is_empty() {
+            return Err("Missing id parameter".to_string());
+        }
+        if members.len() > self.max_members {
+            return Err(format!(
+                "Too many team members, at most {} are allowed",
+                self.max_members
+            ));
+        }
+        Ok(members)
+    }
+}
+
+/// Splits a comma-separated list of ids, dropping blanks and duplicates.
+pub fn parse_member_list(value: &str) -> Vec<String> {
+    let mut members: Vec<String> = Vec::new();
+    for member in value.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+        if !members.iter().any(|m| m == member) {
+            members.push(member.to_string());
+        }
+    }
+    members
+}
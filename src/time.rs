@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
 use chrono_tz::Tz;
 
 use crate::Span;
@@ -26,17 +26,93 @@ pub fn human_time(seconds: u32) -> String {
     }
 }
 
+/// UTC instants spanning the local dates `start..=end` in any timezone (offsets are within a day)
+/// and for any `day_start`. Saturates at the supported date range.
 #[inline(always)]
-pub fn create_timezone_date(
-    tz: &Tz,
-    date: &chrono::NaiveDate,
-    hour: u32,
-    minute: u32,
-    second: u32,
-) -> Result<DateTime<Tz>, String> {
-    tz.with_ymd_and_hms(date.year(), date.month(), date.day(), hour, minute, second)
-        .single()
-        .ok_or_else(|| "Invalid date/time".to_string())
+pub fn utc_window(start: NaiveDate, end: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start = start
+        .checked_sub_signed(Duration::days(1))
+        .unwrap_or(NaiveDate::MIN)
+        .and_time(NaiveTime::MIN);
+    let end = end
+        .checked_add_signed(Duration::days(3))
+        .map_or(NaiveDateTime::MAX, |end| end.and_time(NaiveTime::MIN))
+        .checked_sub_signed(Duration::seconds(1))
+        .unwrap_or(NaiveDateTime::MIN);
+    (start.and_utc(), end.and_utc())
+}
+
+/// Like `utc_window`, but a range ending on a date that may still be today somewhere is
+/// placed before the UTC date `today` instead, keeping only its length. Local dates differ
+/// by a day between timezones while the lengths of relative ranges do not, so every
+/// timezone then shares the same window.
+pub fn fetch_window(
+    start: NaiveDate,
+    end: NaiveDate,
+    today: NaiveDate,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let yesterday = today.pred_opt().unwrap_or(today);
+    if end < yesterday {
+        return utc_window(start, end);
+    }
+    // the local range starts at most a day before `today - length`, which the day of slack
+    // in `utc_window` covers
+    let shared_start = today
+        .checked_sub_signed(end - start + Duration::days(1))
+        .unwrap_or(NaiveDate::MIN);
+    utc_window(shared_start, today)
+}
+
+/// Maps instants to the days of the heatmap: local dates in `tz`, rolling over at `day_start`
+/// instead of midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Calendar {
+    pub tz: Tz,
+    pub day_start: NaiveTime,
+}
+
+impl Calendar {
+    pub fn day_of(&self, instant: &DateTime<Tz>) -> NaiveDate {
+        (instant.naive_local() - self.day_start.signed_duration_since(NaiveTime::MIN)).date()
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.day_of(&Utc::now().with_timezone(&self.tz))
+    }
+
+    /// The instant `date` begins, or the first valid instant after it when a DST change
+    /// skips that local time.
+    fn start_of(&self, date: NaiveDate) -> Option<DateTime<Tz>> {
+        let start = date.and_time(self.day_start);
+        (0..=2).find_map(|hours| {
+            self.tz
+                .from_local_datetime(&(start + Duration::hours(hours)))
+                .earliest()
+        })
+    }
+}
+
+pub const DAYS_PER_WEEK: usize = 7;
+
+/// Row of `weekday` in grids with a row per weekday, starting on `week_start`.
+pub fn weekday_row(weekday: Weekday, week_start: Weekday) -> usize {
+    weekday.days_since(week_start) as usize
+}
+
+/// Parses the `day_start` parameter (`HH:MM`) into the local time days start at.
+pub fn parse_day_start(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|_| "Invalid day_start parameter, must be a time like 03:00".to_string())
+}
+
+/// Parses the `week_start` parameter into the weekday of the first grid row.
+pub fn parse_week_start(value: &str) -> Result<Weekday, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "sunday" | "sun" => Ok(Weekday::Sun),
+        "monday" | "mon" => Ok(Weekday::Mon),
+        "saturday" | "sat" => Ok(Weekday::Sat),
+        _ => Err("Invalid week_start parameter, must be sunday, monday or saturday".to_string()),
+    }
 }
 
 #[inline(always)]
@@ -52,7 +128,7 @@ pub fn generate_date_range(start: NaiveDate, end: NaiveDate) -> Vec<NaiveDate> {
 
 pub fn process_span_into_buckets(
     span: &Span,
-    tz: &Tz,
+    calendar: &Calendar,
     day_buckets: &mut HashMap<chrono::NaiveDate, u32>,
 ) {
     let start_dt_utc =
@@ -72,47 +148,76 @@ pub fn process_span_into_buckets(
         }
     };
 
-    let start_local = start_dt_utc.with_timezone(tz);
-    let end_local = end_dt_utc.with_timezone(tz);
-    let start_date = start_local.date_naive();
-    let end_date = end_local.date_naive();
+    let start_local = start_dt_utc.with_timezone(&calendar.tz);
+    let end_local = end_dt_utc.with_timezone(&calendar.tz);
+    let start_date = calendar.day_of(&start_local);
+    let end_date = calendar.day_of(&end_local);
 
     if start_date == end_date {
         *day_buckets.entry(start_date).or_insert(0) += span.duration.round() as u32;
     } else {
-        split_span_across_days(span, &start_local, &end_local, tz, day_buckets);
+        split_span_across_days(span, &start_local, end_date, calendar, day_buckets);
     }
 }
 
 fn split_span_across_days(
     span: &Span,
     start_local: &chrono::DateTime<Tz>,
-    end_local: &chrono::DateTime<Tz>,
-    tz: &Tz,
+    end_date: NaiveDate,
+    calendar: &Calendar,
     day_buckets: &mut HashMap<chrono::NaiveDate, u32>,
 ) {
     let mut current = *start_local;
+    let mut current_date = calendar.day_of(start_local);
     let mut remaining = span.duration;
-    let end_date = end_local.date_naive();
 
-    while current.date_naive() < end_date {
-        let next_midnight = match tz
-            .with_ymd_and_hms(current.year(), current.month(), current.day(), 23, 59, 59)
-            .single()
-        {
+    while current_date < end_date {
+        let next_date = current_date + Duration::days(1);
+        let next_start = match calendar.start_of(next_date) {
             Some(dt) => dt,
             None => {
-                eprintln!("Invalid next midnight date");
+                eprintln!("Invalid start of day {}", next_date);
                 break;
             }
         };
 
-        let seconds = (next_midnight.timestamp() - current.timestamp() + 1) as f64;
+        let seconds = (next_start.timestamp() - current.timestamp()) as f64;
         let to_add = seconds.min(remaining).round() as u32;
-        *day_buckets.entry(current.date_naive()).or_insert(0) += to_add;
+        *day_buckets.entry(current_date).or_insert(0) += to_add;
         remaining -= seconds;
-        current = next_midnight + Duration::seconds(1);
+        current = next_start;
+        current_date = next_date;
     }
 
     *day_buckets.entry(end_date).or_insert(0) += remaining.round() as u32;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_window_saturates_instead_of_overflowing() {
+        let (start, end) = utc_window(NaiveDate::MIN, NaiveDate::MAX);
+        assert!(start < end);
+    }
+
+    #[test]
+    fn recent_windows_are_shared_by_every_timezone() {
+        let date = |month, day| NaiveDate::from_ymd_opt(2026, month, day).unwrap();
+        let today = date(10, 17);
+        let days = |days| Duration::days(days);
+
+        // the last year in London and in New York, where it is still the day before
+        let london = fetch_window(today - days(364), today, today);
+        let new_york = fetch_window(today - days(365), today - days(1), today);
+        assert_eq!(london, new_york);
+        assert_eq!(london, utc_window(today - days(365), today));
+
+        // past ranges keep their own window
+        assert_eq!(
+            fetch_window(date(3, 1), date(3, 31), today),
+            utc_window(date(3, 1), date(3, 31))
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use metrics::{counter, gauge, histogram};
+use moka::sync::Cache;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::{Layer, Service};
+
+use crate::config::{Config, MAX_BACKOFF_SECONDS};
+use crate::source::{ActivitySource, SourceFuture};
+use crate::{FetchUserSpansError, UserDateRange};
+
+/// Longest the upstream is left alone for, whatever the settings or the upstream ask for.
+const MAX_BACKOFF: Duration = Duration::from_secs(MAX_BACKOFF_SECONDS);
+
+/// The instant `duration` from now, capped at `MAX_BACKOFF` so it cannot overflow.
+fn from_now(duration: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(duration.min(MAX_BACKOFF)).unwrap_or(now)
+}
+
+pub fn build_http_client(config: &Config) -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .user_agent(config.upstream_user_agent.as_str())
+        .connect_timeout(config.upstream_connect_timeout)
+        .read_timeout(config.upstream_read_timeout)
+        .pool_max_idle_per_host(config.upstream_pool_max_idle_per_host)
+        .pool_idle_timeout(config.upstream_pool_idle_timeout)
+        .connector_layer(ConnectTimingLayer)
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+/// Records how long establishing each new upstream connection takes.
+#[derive(Clone)]
+struct ConnectTimingLayer;
+
+impl<S> Layer<S> for ConnectTimingLayer {
+    type Service = ConnectTiming<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConnectTiming { inner }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectTiming<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for ConnectTiming<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connect_start = Instant::now();
+        let connecting = self.inner.call(request);
+        Box::pin(async move {
+            let result = connecting.await;
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            histogram!("heatmap_upstream_connect_duration_seconds", "outcome" => outcome)
+                .record(connect_start.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
+
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter for the given (1-based) attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let jitter = RandomState::new().hash_one(Instant::now()) % 1000;
+        exponential.mul_f64(jitter as f64 / 1000.0)
+    }
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops calling the upstream after repeated failures, letting a single trial request
+/// through once every `open_duration` until one succeeds.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold,
+            open_duration,
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Whether requests are currently rejected, without claiming the trial request.
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                state.open_until = Some(from_now(self.open_duration));
+                true
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            tracing::info!("Upstream recovered, closing circuit breaker");
+            gauge!("heatmap_upstream_circuit_open").set(0.0);
+        }
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            if state.open_until.is_none() {
+                tracing::warn!(
+                    "Upstream failed {} times in a row, opening circuit breaker",
+                    state.consecutive_failures
+                );
+                counter!("heatmap_upstream_circuit_opened_total").increment(1);
+                gauge!("heatmap_upstream_circuit_open").set(1.0);
+            }
+            state.open_until = Some(from_now(self.open_duration));
+        }
+    }
+}
+
+/// Bounds the upstream requests in flight, both overall and for a single user.
+struct ConcurrencyLimiter {
+    global: Arc<Semaphore>,
+    per_user: Cache<String, Arc<Semaphore>>,
+    per_user_limit: usize,
+}
+
+impl ConcurrencyLimiter {
+    fn new(global_limit: usize, per_user_limit: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(global_limit)),
+            per_user: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_idle(Duration::from_secs(60))
+                .build(),
+            per_user_limit,
+        }
+    }
+
+    async fn acquire(&self, id: &str) -> (OwnedSemaphorePermit, OwnedSemaphorePermit) {
+        let wait_start = Instant::now();
+        let user = self.per_user.get_with(id.to_string(), || {
+            Arc::new(Semaphore::new(self.per_user_limit))
+        });
+        // take the user's permit first so a single user cannot hold every global permit
+        let user_permit = user
+            .acquire_owned()
+            .await
+            .expect("limiter semaphores are never closed");
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("limiter semaphores are never closed");
+        histogram!("heatmap_upstream_limiter_wait_seconds")
+            .record(wait_start.elapsed().as_secs_f64());
+        (user_permit, global_permit)
+    }
+}
+
+/// Wraps a network source with bounded retries, a circuit breaker and concurrency limits,
+/// and stops calling it while it asks us to back off.
+pub struct ResilientSource<S> {
+    inner: S,
+    retry: RetryPolicy,
+    /// Total time a fetch may take, waiting for permits and retries included.
+    deadline: Duration,
+    breaker: CircuitBreaker,
+    limiter: ConcurrencyLimiter,
+    rate_limit_backoff: Duration,
+    throttled_until: Mutex<Option<Instant>>,
+}
+
+impl<S: ActivitySource> ResilientSource<S> {
+    pub fn new(inner: S, config: &Config) -> Self {
+        Self {
+            inner,
+            retry: RetryPolicy {
+                max_attempts: config.upstream_max_attempts,
+                base_delay: config.upstream_retry_base_delay,
+                max_delay: config.upstream_retry_max_delay,
+            },
+            deadline: config.upstream_deadline,
+            breaker: CircuitBreaker::new(
+                config.circuit_breaker_threshold,
+                config.circuit_breaker_open_duration,
+            ),
+            limiter: ConcurrencyLimiter::new(
+                config.upstream_max_concurrency,
+                config.upstream_max_concurrency_per_user,
+            ),
+            rate_limit_backoff: config.upstream_rate_limit_backoff,
+            throttled_until: Mutex::new(None),
+        }
+    }
+
+    /// Time left until the upstream accepts requests again after throttling us.
+    fn throttled_for(&self) -> Option<Duration> {
+        let throttled_until = (*self.throttled_until.lock().unwrap())?;
+        throttled_until
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn check_available(&self, circuit_open: bool) -> Result<(), FetchUserSpansError> {
+        if let Some(remaining) = self.throttled_for() {
+            counter!("heatmap_upstream_errors_total", "type" => "throttled").increment(1);
+            return Err(FetchUserSpansError::RateLimited(Some(remaining)));
+        }
+        if circuit_open {
+            counter!("heatmap_upstream_errors_total", "type" => "circuit_open").increment(1);
+            return Err(FetchUserSpansError::CircuitOpen);
+        }
+        Ok(())
+    }
+
+    fn deadline_exceeded(&self, user_range: &UserDateRange) -> FetchUserSpansError {
+        tracing::error!(
+            "Upstream request for {} did not finish within {:?}",
+            user_range.id,
+            self.deadline
+        );
+        counter!("heatmap_upstream_errors_total", "type" => "deadline").increment(1);
+        FetchUserSpansError::Timeout
+    }
+
+    fn throttle(&self, retry_after: Option<Duration>) -> Duration {
+        let backoff = retry_after
+            .unwrap_or(self.rate_limit_backoff)
+            .min(MAX_BACKOFF);
+        let until = from_now(backoff);
+        let mut throttled_until = self.throttled_until.lock().unwrap();
+        *throttled_until = Some(throttled_until.map_or(until, |current| current.max(until)));
+        backoff
+    }
+}
+
+impl<S: ActivitySource> ActivitySource for ResilientSource<S> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn daily_totals(&self) -> bool {
+        self.inner.daily_totals()
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let deadline = tokio::time::Instant::from_std(from_now(self.deadline));
+            let mut attempt = 1;
+            loop {
+                // fail fast instead of queueing for permits, and check again once they are
+                // held since a queued request may have tripped the breaker meanwhile
+                self.check_available(self.breaker.is_open())?;
+                let permits =
+                    tokio::time::timeout_at(deadline, self.limiter.acquire(&user_range.id))
+                        .await
+                        .map_err(|_| self.deadline_exceeded(user_range))?;
+                self.check_available(!self.breaker.allow())?;
+
+                let attempt_start = Instant::now();
+                let result =
+                    tokio::time::timeout_at(deadline, self.inner.fetch_activity(http, user_range))
+                        .await
+                        .unwrap_or_else(|_| Err(self.deadline_exceeded(user_range)));
+                let err = match result {
+                    Ok(activity) => {
+                        self.breaker.record_success();
+                        return Ok(activity);
+                    }
+                    Err(FetchUserSpansError::RateLimited(retry_after)) => {
+                        let backoff = self.throttle(retry_after);
+                        tracing::warn!("Not calling the upstream for the next {:?}", backoff);
+                        return Err(FetchUserSpansError::RateLimited(Some(backoff)));
+                    }
+                    Err(err) if err.is_retryable() => {
+                        self.breaker.record_failure();
+                        if attempt >= self.retry.max_attempts {
+                            return Err(err);
+                        }
+                        err
+                    }
+                    Err(err) => {
+                        if err.is_answer() {
+                            self.breaker.record_success();
+                        }
+                        return Err(err);
+                    }
+                };
+
+                drop(permits);
+                let delay = self.retry.backoff(attempt);
+                // assume the next attempt takes as long as this one, and skip it when it
+                // would not finish in time anyway
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                if delay + attempt_start.elapsed() >= remaining {
+                    tracing::warn!(
+                        "Not retrying upstream request for {}, {:?} left until the deadline",
+                        user_range.id,
+                        remaining
+                    );
+                    return Err(err);
+                }
+                tracing::warn!(
+                    "Retrying upstream request for {} in {:?} (attempt {}/{})",
+                    user_range.id,
+                    delay,
+                    attempt + 1,
+                    self.retry.max_attempts
+                );
+                counter!("heatmap_upstream_retries_total").increment(1);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::source::FixtureSource;
+
+    /// Fails every fetch after `delay`, counting the attempts.
+    struct SlowSource {
+        delay: Duration,
+        attempts: AtomicU32,
+    }
+
+    impl ActivitySource for SlowSource {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        fn fetch_activity<'a>(
+            &'a self,
+            _http: &'a reqwest::Client,
+            _user_range: &'a UserDateRange,
+        ) -> SourceFuture<'a> {
+            Box::pin(async move {
+                self.attempts.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(self.delay).await;
+                Err(FetchUserSpansError::Fetch)
+            })
+        }
+    }
+
+    fn source() -> ResilientSource<FixtureSource> {
+        resilient(
+            FixtureSource::new(HashMap::from([("alice".to_string(), Ok(Vec::new()))])),
+            1,
+        )
+    }
+
+    fn slow_source(delay: Duration) -> ResilientSource<SlowSource> {
+        let mut source = resilient(
+            SlowSource {
+                delay,
+                attempts: AtomicU32::new(0),
+            },
+            3,
+        );
+        source.deadline = Duration::from_millis(500);
+        source
+    }
+
+    fn resilient<S>(inner: S, max_attempts: u32) -> ResilientSource<S> {
+        ResilientSource {
+            inner,
+            retry: RetryPolicy {
+                max_attempts,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+            deadline: Duration::from_secs(60),
+            breaker: CircuitBreaker::new(1, Duration::from_secs(60)),
+            limiter: ConcurrencyLimiter::new(1, 1),
+            rate_limit_backoff: Duration::from_secs(60),
+            throttled_until: Mutex::new(None),
+        }
+    }
+
+    /// Fetches while every permit is held, so only a fast failure can finish in time.
+    async fn fetch_with_permits_held(
+        source: &ResilientSource<FixtureSource>,
+    ) -> Result<(), FetchUserSpansError> {
+        let _permits = source.limiter.acquire("alice").await;
+        let (http, window) = (reqwest::Client::new(), UserDateRange::test_window("alice"));
+        let fetch = source.fetch_activity(&http, &window);
+        tokio::time::timeout(Duration::from_secs(1), fetch)
+            .await
+            .expect("should fail without waiting for a permit")
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_fail_without_queueing() {
+        let source = source();
+        source.throttle(None);
+
+        assert!(matches!(
+            fetch_with_permits_held(&source).await,
+            Err(FetchUserSpansError::RateLimited(Some(_)))
+        ));
+    }
+
+    #[test]
+    fn huge_backoffs_are_capped() {
+        let source = source();
+        assert_eq!(source.throttle(Some(Duration::MAX)), MAX_BACKOFF);
+
+        let breaker = CircuitBreaker::new(1, Duration::MAX);
+        breaker.record_failure();
+        assert!(breaker.is_open());
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_without_queueing() {
+        let source = source();
+        source.breaker.record_failure();
+
+        assert!(matches!(
+            fetch_with_permits_held(&source).await,
+            Err(FetchUserSpansError::CircuitOpen)
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetches_give_up_at_the_deadline() {
+        let source = slow_source(Duration::from_secs(60));
+        let (http, window) = (reqwest::Client::new(), UserDateRange::test_window("alice"));
+        let fetch = source.fetch_activity(&http, &window);
+
+        let result = tokio::time::timeout(Duration::from_secs(2), fetch)
+            .await
+            .expect("should stop at the deadline");
+        assert!(matches!(result, Err(FetchUserSpansError::Timeout)));
+        assert_eq!(source.inner.attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_that_cannot_finish_in_time_are_skipped() {
+        // a second 300ms attempt would end after the 500ms deadline
+        let source = slow_source(Duration::from_millis(300));
+        let (http, window) = (reqwest::Client::new(), UserDateRange::test_window("alice"));
+
+        assert!(matches!(
+            source.fetch_activity(&http, &window).await,
+            Err(FetchUserSpansError::Fetch)
+        ));
+        assert_eq!(source.inner.attempts.load(Ordering::Relaxed), 1);
+    }
+}
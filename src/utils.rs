@@ -1,8 +1,11 @@
+use std::cmp::Ordering;
+
 use axum::http::{HeaderMap, HeaderValue};
 use chrono::Datelike;
 use reqwest::header;
 
-use crate::time::human_time;
+use crate::source::Unit;
+use crate::team::Aggregation;
 
 use crate::CACHE_HEADER;
 
@@ -25,17 +28,84 @@ fn format_day_suffix(day: u32) -> &'static str {
     }
 }
 
-pub fn format_cell_label(date: &chrono::NaiveDate, seconds: u32) -> String {
+pub fn format_cell_label(
+    date: &chrono::NaiveDate,
+    value: u32,
+    aggregation: Aggregation,
+    unit: Unit,
+) -> String {
     let date_str = date.format("%B %-d").to_string();
     let suffix = format_day_suffix(date.day());
 
-    if seconds > 0 {
-        format!("{} on {}{}", human_time(seconds), date_str, suffix)
+    if value > 0 {
+        format!(
+            "{} on {}{}",
+            aggregation.describe(value, unit),
+            date_str,
+            suffix
+        )
     } else {
         format!("No activity on {}{}", date_str, suffix)
     }
 }
 
+/// Labels a punch card slot, e.g. "2h 5m on Mondays at 14:00".
+pub fn format_hour_label(
+    weekday: chrono::Weekday,
+    hour: u32,
+    value: u32,
+    aggregation: Aggregation,
+    unit: Unit,
+) -> String {
+    let slot = format!("{}s at {:02}:00", weekday_name(weekday), hour);
+    if value > 0 {
+        format!("{} on {}", aggregation.describe(value, unit), slot)
+    } else {
+        format!("No activity on {}", slot)
+    }
+}
+
+fn weekday_name(weekday: chrono::Weekday) -> &'static str {
+    match weekday {
+        chrono::Weekday::Mon => "Monday",
+        chrono::Weekday::Tue => "Tuesday",
+        chrono::Weekday::Wed => "Wednesday",
+        chrono::Weekday::Thu => "Thursday",
+        chrono::Weekday::Fri => "Friday",
+        chrono::Weekday::Sat => "Saturday",
+        chrono::Weekday::Sun => "Sunday",
+    }
+}
+
+/// Shows both values and how the first differs from the baseline.
+pub fn format_comparison_label(
+    date: &chrono::NaiveDate,
+    value: u32,
+    baseline: u32,
+    aggregation: Aggregation,
+    unit: Unit,
+) -> String {
+    let date_str = date.format("%B %-d").to_string();
+    let suffix = format_day_suffix(date.day());
+    if value == 0 && baseline == 0 {
+        return format!("No activity on {}{}", date_str, suffix);
+    }
+    let delta = match value.cmp(&baseline) {
+        Ordering::Greater => format!("+{}", aggregation.describe(value - baseline, unit)),
+        Ordering::Less => format!("-{}", aggregation.describe(baseline - value, unit)),
+        Ordering::Equal => "no change".to_string(),
+    };
+
+    format!(
+        "{} vs {} ({}) on {}{}",
+        aggregation.describe(value, unit),
+        aggregation.describe(baseline, unit),
+        delta,
+        date_str,
+        suffix
+    )
+}
+
 pub fn validate_ranges(ranges_str: &str) -> Result<Vec<u32>, String> {
     let ranges = ranges_str
         .split(',')
@@ -0,0 +1,176 @@
+use std::time::Instant;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::NaiveDate;
+use metrics::{counter, histogram};
+use reqwest::header::AUTHORIZATION;
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::source::{
+    Activity, ActivitySource, SourceFuture, api_error, check_status, filtered_url,
+    map_request_error,
+};
+use crate::{DayBuckets, FetchUserSpansError, UserDateRange};
+
+#[derive(Debug, Deserialize)]
+struct SummariesResponse {
+    #[serde(default)]
+    data: Vec<Summary>,
+    #[serde(default)]
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Summary {
+    grand_total: GrandTotal,
+    range: SummaryRange,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrandTotal {
+    #[serde(default)]
+    total_seconds: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryRange {
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    start: Option<String>,
+}
+
+impl SummaryRange {
+    fn date(&self) -> Option<NaiveDate> {
+        self.date
+            .as_deref()
+            .or(self.start.as_deref())
+            .and_then(|value| value.get(..10))
+            .and_then(|value| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok())
+    }
+}
+
+/// Daily totals from the WakaTime-compatible `/summaries` endpoint (Wakapi, WakaTime).
+pub struct WakapiSource {
+    config: Config,
+}
+
+impl WakapiSource {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// The summaries URL for the window, encoding the parameters since timezone names such as
+    /// `Etc/GMT+3` contain characters with a meaning in query strings.
+    fn url(&self, user_range: &UserDateRange) -> Result<Url, FetchUserSpansError> {
+        let mut url = filtered_url(
+            &self.config.summaries_url(&user_range.id),
+            &user_range.filter,
+        )?;
+        url.query_pairs_mut()
+            .append_pair("start", &user_range.start.date_naive().to_string())
+            .append_pair("end", &user_range.end.date_naive().to_string())
+            .append_pair(
+                "timezone",
+                user_range.timezone.map_or("UTC", |tz| tz.name()),
+            );
+        Ok(url)
+    }
+
+    async fn fetch(
+        &self,
+        http: &reqwest::Client,
+        user_range: &UserDateRange,
+    ) -> Result<DayBuckets, FetchUserSpansError> {
+        let fetch_start = Instant::now();
+        let url = self.url(user_range)?;
+
+        let mut request = http.get(url);
+        if let Some(api_key) = &self.config.wakapi_api_key {
+            request = request.header(
+                AUTHORIZATION,
+                format!("Basic {}", BASE64.encode(api_key.as_bytes())),
+            );
+        }
+
+        let resp = request.send().await.map_err(map_request_error)?;
+        let status = resp.status();
+        check_status(&resp)?;
+
+        if status == StatusCode::FORBIDDEN {
+            tracing::warn!("User {} has disabled public stats", user_range.id);
+            counter!("heatmap_upstream_errors_total", "type" => "privacy").increment(1);
+            return Err(FetchUserSpansError::DisabledPublicStats);
+        }
+
+        let json_resp = match resp.json::<SummariesResponse>().await {
+            Ok(json_resp) => json_resp,
+            Err(_) if !status.is_success() => return Err(api_error(status, "", &user_range.id)),
+            Err(err) => {
+                tracing::error!("Error parsing JSON: {:?}", err);
+                counter!("heatmap_upstream_errors_total", "type" => "parse").increment(1);
+                return Err(FetchUserSpansError::Parse);
+            }
+        };
+
+        if !status.is_success() || !json_resp.error.is_empty() {
+            return Err(api_error(status, &json_resp.error, &user_range.id));
+        }
+
+        let mut day_buckets = DayBuckets::new();
+        for summary in &json_resp.data {
+            let Some(date) = summary.range.date() else {
+                tracing::warn!("Skipping summary without a valid date");
+                continue;
+            };
+            *day_buckets.entry(date).or_insert(0) +=
+                summary.grand_total.total_seconds.round() as u32;
+        }
+
+        histogram!("heatmap_upstream_request_duration_seconds")
+            .record(fetch_start.elapsed().as_secs_f64());
+        Ok(day_buckets)
+    }
+}
+
+impl ActivitySource for WakapiSource {
+    fn name(&self) -> &'static str {
+        "wakapi"
+    }
+
+    fn daily_totals(&self) -> bool {
+        true
+    }
+
+    fn fetch_activity<'a>(
+        &'a self,
+        http: &'a reqwest::Client,
+        user_range: &'a UserDateRange,
+    ) -> SourceFuture<'a> {
+        Box::pin(async move { self.fetch(http, user_range).await.map(Activity::Days) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_parameters_are_encoded() {
+        let mut window = UserDateRange::test_window("alice");
+        window.timezone = Some(chrono_tz::Etc::GMTPlus3);
+        let source = WakapiSource::new(&Config::from_env().unwrap());
+
+        let url = source.url(&window).unwrap();
+        assert!(url.query().unwrap().contains("timezone=Etc%2FGMT%2B3"));
+        assert!(
+            url.query_pairs()
+                .any(|(name, value)| name == "timezone" && value == "Etc/GMT+3")
+        );
+    }
+}